@@ -0,0 +1,251 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use reqwest::multipart::{Form, Part};
+use serde::{Deserialize, Serialize};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::errors::Error;
+use crate::ipfs::IpfsApi;
+
+/// Options of `ipfs add`. Unset options use the defaults of the node.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AddOptions {
+    /// 0 or 1. CIDv1 links are the same for every multibase a gateway may use.
+    pub cid_version: Option<u32>,
+    /// Store the file data in raw leaves instead of wrapping it in protobuf nodes.
+    pub raw_leaves: Option<bool>,
+    /// `size-<bytes>` or `rabin[-<min>-<avg>-<max>]`.
+    pub chunker: Option<String>,
+    /// Multihash function, e.g. `sha2-256` or `blake2b-256`.
+    pub hash: Option<String>,
+    /// Only compute the CID without storing or pinning anything.
+    pub only_hash: Option<bool>,
+    /// Use the trickle DAG layout, which suits streaming media.
+    pub trickle: Option<bool>,
+}
+
+impl AddOptions {
+    /// These options with the unset ones taken from `defaults`.
+    pub fn or(&self, defaults: &AddOptions) -> AddOptions {
+        AddOptions {
+            cid_version: self.cid_version.or(defaults.cid_version),
+            raw_leaves: self.raw_leaves.or(defaults.raw_leaves),
+            chunker: self.chunker.clone().or_else(|| defaults.chunker.clone()),
+            hash: self.hash.clone().or_else(|| defaults.hash.clone()),
+            only_hash: self.only_hash.or(defaults.only_hash),
+            trickle: self.trickle.or(defaults.trickle),
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.only_hash == Some(true)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(version) = self.cid_version {
+            if version > 1 {
+                return Err(format!("cid_version: {} is not 0 or 1", version));
+            }
+        }
+        if let Some(chunker) = &self.chunker {
+            if !chunker.starts_with("size-") && !chunker.starts_with("rabin") {
+                return Err(format!(
+                    "chunker: '{}' is neither size-<bytes> nor rabin[-<min>-<avg>-<max>]",
+                    chunker
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses `--cid-version=<0|1>`, `--raw-leaves[=<bool>]`, `--chunker=<chunker>`,
+    /// `--hash=<function>`, `--only-hash` and `--trickle` flags of a command.
+    pub fn parse_flags<'a>(args: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut options = Self::default();
+        for arg in args {
+            if !arg.starts_with("--") {
+                return Err(format!("'{}' is not a flag", arg));
+            }
+            let flag = &arg[2..];
+            let mut parts = flag.splitn(2, '=');
+            let (name, value) = (parts.next().unwrap_or(""), parts.next());
+            let switch = || match value {
+                None => Ok(true),
+                Some(value) => value
+                    .parse::<bool>()
+                    .map_err(|_| format!("--{} expects true or false", name)),
+            };
+            match (name, value) {
+                ("cid-version", Some(value)) => {
+                    let version = value
+                        .parse()
+                        .map_err(|_| "--cid-version expects 0 or 1".to_string())?;
+                    options.cid_version = Some(version);
+                }
+                ("raw-leaves", _) => options.raw_leaves = Some(switch()?),
+                ("chunker", Some(value)) => options.chunker = Some(value.to_string()),
+                ("hash", Some(value)) => options.hash = Some(value.to_string()),
+                ("only-hash", _) => options.only_hash = Some(switch()?),
+                ("trickle", _) => options.trickle = Some(switch()?),
+                _ => return Err(format!("unknown flag '{}'", arg)),
+            }
+        }
+        options.validate()?;
+        Ok(options)
+    }
+
+    fn query(&self, pin: bool) -> Vec<(&'static str, String)> {
+        let mut query = vec![("pin", pin.to_string())];
+        if let Some(version) = self.cid_version {
+            query.push(("cid-version", version.to_string()));
+        }
+        if let Some(raw_leaves) = self.raw_leaves {
+            query.push(("raw-leaves", raw_leaves.to_string()));
+        }
+        if let Some(chunker) = &self.chunker {
+            query.push(("chunker", chunker.clone()));
+        }
+        if let Some(hash) = &self.hash {
+            query.push(("hash", hash.clone()));
+        }
+        if let Some(only_hash) = self.only_hash {
+            query.push(("only-hash", only_hash.to_string()));
+        }
+        if let Some(trickle) = self.trickle {
+            query.push(("trickle", trickle.to_string()));
+        }
+        query
+    }
+}
+
+/// A line of the `add` response.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AddResponse {
+    hash: Option<String>,
+}
+
+/// Adds the file at `path` through `api` and returns its CID.
+/// The file is streamed, so big videos don't have to fit into memory.
+pub async fn add_file(
+    api: &IpfsApi,
+    path: &Path,
+    options: &AddOptions,
+    pin: bool,
+) -> Result<String, Error> {
+    let file = tokio::fs::File::open(path).await?;
+    let stream = FramedRead::new(file, BytesCodec::new()).map_ok(Bytes::from);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let form = Form::new().part(
+        "file",
+        Part::stream(reqwest::Body::wrap_stream(stream)).file_name(name),
+    );
+
+    let request = api
+        .request("add")
+        .query(&options.query(pin))
+        .multipart(form);
+    let body = api
+        .send(request)
+        .await?
+        .text()
+        .await
+        .map_err(Error::IpfsApi)?;
+
+    // One JSON object per line, the last one with a hash is the added file.
+    body.lines()
+        .filter_map(|line| serde_json::from_str::<AddResponse>(line).ok())
+        .filter_map(|resp| resp.hash)
+        .last()
+        .ok_or(Error::EmptyIpfsResponse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(args: &[&str]) -> Result<AddOptions, String> {
+        AddOptions::parse_flags(args.iter().copied())
+    }
+
+    #[test]
+    fn parses_flags() {
+        assert_eq!(
+            flags(&[
+                "--cid-version=1",
+                "--raw-leaves",
+                "--chunker=size-1048576",
+                "--hash=blake2b-256",
+                "--trickle=false",
+            ])
+            .unwrap(),
+            AddOptions {
+                cid_version: Some(1),
+                raw_leaves: Some(true),
+                chunker: Some("size-1048576".to_string()),
+                hash: Some("blake2b-256".to_string()),
+                only_hash: None,
+                trickle: Some(false),
+            }
+        );
+        assert!(flags(&["--only-hash"]).unwrap().is_dry_run());
+        assert_eq!(flags(&[]).unwrap(), AddOptions::default());
+    }
+
+    #[test]
+    fn refuses_invalid_flags() {
+        assert!(flags(&["--cid-version"]).is_err());
+        assert!(flags(&["--cid-version=2"]).is_err());
+        assert!(flags(&["--chunker=buzhash"]).is_err());
+        assert!(flags(&["--trickle=maybe"]).is_err());
+        assert!(flags(&["--pin=false"]).is_err());
+        assert!(flags(&["--trickle", "now"]).is_err());
+    }
+
+    #[test]
+    fn command_options_win_over_the_config() {
+        let defaults = AddOptions {
+            cid_version: Some(1),
+            raw_leaves: Some(true),
+            chunker: Some("rabin".to_string()),
+            ..AddOptions::default()
+        };
+        let options = AddOptions {
+            cid_version: Some(0),
+            trickle: Some(true),
+            ..AddOptions::default()
+        };
+        assert_eq!(
+            options.or(&defaults),
+            AddOptions {
+                cid_version: Some(0),
+                raw_leaves: Some(true),
+                chunker: Some("rabin".to_string()),
+                hash: None,
+                only_hash: None,
+                trickle: Some(true),
+            }
+        );
+    }
+
+    #[test]
+    fn validates_the_config_options() {
+        assert!(AddOptions::default().validate().is_ok());
+        let rabin = AddOptions {
+            chunker: Some("rabin-262144-524288-1048576".to_string()),
+            ..AddOptions::default()
+        };
+        assert!(rabin.validate().is_ok());
+        let cid_version = AddOptions {
+            cid_version: Some(3),
+            ..AddOptions::default()
+        };
+        assert!(cid_version.validate().is_err());
+    }
+}
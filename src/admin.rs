@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use matrix_sdk::{
+    identifiers::{RoomId, UserId},
+    Client, Room,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::errors::Error;
+use crate::ipfs::IpfsApi;
+use crate::nodes::IpfsNodes;
+use crate::permissions;
+use crate::reload::SharedConfig;
+use crate::reply::{self, ReplyTarget};
+
+/// Power level that makes a member of the admin room a bot admin if no `users` are configured.
+const ADMIN_POWER_LEVEL: i64 = 100;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Room where admin commands are accepted and alerts are posted.
+    pub room: Option<String>,
+    /// Users allowed to run admin commands. Empty means the room admins of the admin room.
+    pub users: Vec<String>,
+    /// How often the IPFS node is checked in seconds.
+    pub monitor_interval_secs: u64,
+    /// Alert when the IPFS repository uses this much of its `StorageMax` in percent.
+    pub repo_usage_alert_percent: u64,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            room: None,
+            users: Vec::new(),
+            monitor_interval_secs: 60,
+            repo_usage_alert_percent: 90,
+        }
+    }
+}
+
+impl AdminConfig {
+    pub fn is_admin_room(&self, room_id: &RoomId) -> bool {
+        self.room.as_deref() == Some(room_id.to_string().as_str())
+    }
+
+    /// Whether `user_id` may run admin commands in `room`.
+    pub fn is_admin(&self, room: &Room, user_id: &UserId) -> bool {
+        if !self.is_admin_room(&room.room_id) {
+            return false;
+        }
+        if self.users.is_empty() {
+            permissions::user_level(room, user_id) >= ADMIN_POWER_LEVEL
+        } else {
+            self.users.iter().any(|u| *u == user_id.to_string())
+        }
+    }
+}
+
+/// Users the bot ignores, persisted as JSON next to the session.
+pub struct BanList {
+    path: PathBuf,
+    users: Mutex<HashSet<String>>,
+}
+
+impl BanList {
+    pub fn load(path: PathBuf) -> Self {
+        let users = if path.exists() {
+            let f = OpenOptions::new().read(true).open(&path).unwrap();
+            serde_json::from_reader(f).expect("ban list should be proper JSON")
+        } else {
+            HashSet::new()
+        };
+        Self {
+            path,
+            users: Mutex::new(users),
+        }
+    }
+
+    pub fn is_banned(&self, user_id: &UserId) -> bool {
+        self.users.lock().unwrap().contains(&user_id.to_string())
+    }
+
+    pub fn count(&self) -> usize {
+        self.users.lock().unwrap().len()
+    }
+
+    /// Bans the user and returns `false` if they were banned already.
+    pub fn ban(&self, user_id: &UserId) -> bool {
+        let mut users = self.users.lock().unwrap();
+        if !users.insert(user_id.to_string()) {
+            return false;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        let result = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(&f, &*users).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, &self.path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Unable to persist ban list: {}", e);
+        }
+        true
+    }
+}
+
+/// Posts operational alerts into the admin room, if there is one.
+#[derive(Clone)]
+pub struct Alerts {
+    client: Client,
+    config: SharedConfig,
+}
+
+impl Alerts {
+    pub fn new(client: Client, config: SharedConfig) -> Self {
+        Self { client, config }
+    }
+
+    pub async fn send(&self, body: String) {
+        warn!("{}", body);
+        let room = match self.config.get().admin.room.clone() {
+            Some(room) => room,
+            None => return,
+        };
+        match RoomId::try_from(room.as_str()) {
+            Ok(room_id) => {
+                reply::send_notice(&self.client, &room_id, body, &ReplyTarget::default()).await;
+            }
+            Err(e) => warn!("Invalid admin room {}: {:?}", room, e),
+        }
+    }
+}
+
+/// The parts of `repo/stat` the bot cares about.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RepoStat {
+    pub repo_size: u64,
+    pub storage_max: u64,
+    pub num_objects: u64,
+}
+
+pub async fn repo_stat(api: &IpfsApi) -> Result<RepoStat, Error> {
+    api.send(api.request("repo/stat"))
+        .await?
+        .json()
+        .await
+        .map_err(Error::IpfsApi)
+}
+
+/// Runs the garbage collection of the IPFS node and returns how many blocks got removed.
+pub async fn repo_gc(api: &IpfsApi) -> Result<usize, Error> {
+    let body = api
+        .send(api.request("repo/gc"))
+        .await?
+        .text()
+        .await
+        .map_err(Error::IpfsApi)?;
+    Ok(body.lines().filter(|line| !line.trim().is_empty()).count())
+}
+
+/// Periodically checks that the primary IPFS node is reachable and has space left and alerts
+/// on changes.
+pub fn spawn_monitor(nodes: Arc<IpfsNodes>, config: SharedConfig, alerts: Alerts) {
+    tokio::spawn(async move {
+        let mut reachable = true;
+        let mut quota_exceeded = false;
+        loop {
+            let current = config.get();
+            match repo_stat(&nodes.primary().client).await {
+                Ok(stat) => {
+                    if !reachable {
+                        alerts
+                            .send("IPFS node is reachable again".to_string())
+                            .await;
+                    }
+                    reachable = true;
+
+                    let limit = stat.storage_max / 100 * current.admin.repo_usage_alert_percent;
+                    let exceeded = stat.storage_max > 0 && stat.repo_size >= limit;
+                    if exceeded && !quota_exceeded {
+                        alerts
+                            .send(format!(
+                                "IPFS repository uses {} of {} bytes",
+                                stat.repo_size, stat.storage_max
+                            ))
+                            .await;
+                    }
+                    quota_exceeded = exceeded;
+                }
+                Err(e) => {
+                    if reachable {
+                        alerts
+                            .send(format!("IPFS node is unreachable: {}", e))
+                            .await;
+                    }
+                    reachable = false;
+                }
+            }
+
+            let interval = current.admin.monitor_interval_secs.max(1);
+            tokio::time::delay_for(Duration::from_secs(interval)).await;
+        }
+    });
+}
@@ -0,0 +1,341 @@
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::jobs::now;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// Where the JSON Lines are written to, `audit.jsonl` in the store if unset.
+    pub path: Option<PathBuf>,
+    /// The log is rotated before it grows beyond this size.
+    pub max_file_size_bytes: u64,
+    /// How many rotated files are kept besides the current one.
+    pub max_files: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+            max_file_size_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Archive,
+    ArchiveFailed,
+    Unpin,
+    RepinAll,
+    Gc,
+}
+
+/// One line of the audit log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Seconds since the UNIX epoch.
+    pub time: u64,
+    pub action: AuditAction,
+    /// The user that caused the action, or the part of the bot for automatic actions.
+    pub actor: String,
+    /// `true` if nobody asked for the action directly.
+    pub automatic: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    /// The media event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_event_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(action: AuditAction, actor: String) -> Self {
+        Self {
+            time: now(),
+            action,
+            actor,
+            automatic: false,
+            room_id: None,
+            event_id: None,
+            command_event_id: None,
+            cid: None,
+            detail: None,
+        }
+    }
+}
+
+/// Which entries `!ipfs admin audit` shows.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub room: Option<String>,
+    /// Seconds since the UNIX epoch, inclusive.
+    pub since: Option<u64>,
+    /// Seconds since the UNIX epoch, exclusive.
+    pub until: Option<u64>,
+}
+
+fn parse_date(value: &str) -> Result<u64, String> {
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|e| format!("'{}' is not a YYYY-MM-DD date: {}", value, e))?;
+    Ok(date.and_hms(0, 0, 0).timestamp().max(0) as u64)
+}
+
+impl AuditFilter {
+    /// Parses `user=<mxid>`, `room=<room id>`, `since=<date>` and `until=<date>`,
+    /// with dates as `YYYY-MM-DD` and `until` including the whole day.
+    pub fn parse<'a>(args: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for arg in args {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("user"), Some(user)) => filter.user = Some(user.to_string()),
+                (Some("room"), Some(room)) => filter.room = Some(room.to_string()),
+                (Some("since"), Some(date)) => filter.since = Some(parse_date(date)?),
+                (Some("until"), Some(date)) => {
+                    filter.until = Some(parse_date(date)? + 24 * 60 * 60)
+                }
+                _ => return Err(format!("unknown filter '{}'", arg)),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.user.as_ref().map_or(true, |user| *user == entry.actor)
+            && self
+                .room
+                .as_ref()
+                .map_or(true, |room| Some(room) == entry.room_id.as_ref())
+            && self.since.map_or(true, |since| entry.time >= since)
+            && self.until.map_or(true, |until| entry.time < until)
+    }
+}
+
+/// Append-only JSON Lines log of archive and unpin actions with size based rotation.
+pub struct AuditLog {
+    path: PathBuf,
+    config: AuditConfig,
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    /// The path and rotation settings are taken at startup.
+    pub fn new(config: &AuditConfig, store: &Path) -> Self {
+        Self {
+            path: config
+                .path
+                .clone()
+                .unwrap_or_else(|| store.join("audit.jsonl")),
+            config: config.clone(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Moves `audit.jsonl` to `audit.jsonl.1`, `audit.jsonl.1` to `audit.jsonl.2` and so on,
+    /// dropping the oldest file.
+    fn rotate(&self) -> std::io::Result<()> {
+        let oldest = self.rotated(self.config.max_files);
+        if oldest.exists() {
+            fs::remove_file(oldest)?;
+        }
+        for n in (1..self.config.max_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        if self.config.max_files == 0 {
+            fs::remove_file(&self.path)
+        } else {
+            fs::rename(&self.path, self.rotated(1))
+        }
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        if !self.config.enabled {
+            return;
+        }
+        let _guard = self.lock.lock().unwrap();
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.config.max_file_size_bytes {
+            if let Err(e) = self.rotate() {
+                warn!("Unable to rotate the audit log: {}", e);
+            }
+        }
+        let result = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(line.as_bytes()));
+        if let Err(e) = result {
+            warn!("Unable to write the audit log: {}", e);
+        }
+    }
+
+    /// The latest `limit` entries matching `filter`, oldest first.
+    pub fn query(&self, filter: &AuditFilter, limit: usize) -> Vec<AuditEntry> {
+        let _guard = self.lock.lock().unwrap();
+        let mut files: Vec<PathBuf> = (1..=self.config.max_files)
+            .rev()
+            .map(|n| self.rotated(n))
+            .collect();
+        files.push(self.path.clone());
+
+        let mut entries = Vec::new();
+        for path in files {
+            let f = match OpenOptions::new().read(true).open(&path) {
+                Ok(f) => f,
+                Err(_) => continue,
+            };
+            for line in BufReader::new(f).lines() {
+                let entry = line
+                    .ok()
+                    .and_then(|line| serde_json::from_str::<AuditEntry>(&line).ok());
+                match entry {
+                    Some(entry) if filter.matches(&entry) => entries.push(entry),
+                    Some(_) => {}
+                    None => warn!("Skipping invalid line in {}", path.display()),
+                }
+            }
+        }
+        let skip = entries.len().saturating_sub(limit);
+        entries.split_off(skip)
+    }
+}
+
+/// A line of the `!ipfs admin audit` answer.
+pub fn describe(entry: &AuditEntry) -> String {
+    let time = NaiveDateTime::from_timestamp(entry.time as i64, 0).format("%Y-%m-%d %H:%M:%S");
+    let mut line = format!("{} {:?} by {}", time, entry.action, entry.actor);
+    if entry.automatic {
+        line.push_str(" (automatic)");
+    }
+    if let Some(room_id) = &entry.room_id {
+        line.push_str(&format!(" in {}", room_id));
+    }
+    if let Some(event_id) = &entry.event_id {
+        line.push_str(&format!(" for {}", event_id));
+    }
+    if let Some(cid) = &entry.cid {
+        line.push_str(&format!(": {}", cid));
+    }
+    if let Some(detail) = &entry.detail {
+        line.push_str(&format!(" ({})", detail));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn parses_filters() {
+        let filter = AuditFilter::parse(
+            "user=@alice:example.com room=!room:example.com since=2020-01-02 until=2020-01-02"
+                .split_whitespace(),
+        )
+        .unwrap();
+        assert_eq!(
+            filter,
+            AuditFilter {
+                user: Some("@alice:example.com".to_string()),
+                room: Some("!room:example.com".to_string()),
+                since: Some(1_577_923_200),
+                until: Some(1_577_923_200 + 24 * 60 * 60),
+            }
+        );
+        assert_eq!(
+            AuditFilter::parse(std::iter::empty()).unwrap(),
+            AuditFilter::default()
+        );
+        assert!(AuditFilter::parse(std::iter::once("since=yesterday")).is_err());
+        assert!(AuditFilter::parse(std::iter::once("user")).is_err());
+        assert!(AuditFilter::parse(std::iter::once("colour=blue")).is_err());
+    }
+
+    #[test]
+    fn rotates_and_queries_across_files() {
+        let store = env::temp_dir().join(format!("ipfs-bot-test-{}-audit", process::id()));
+        fs::create_dir_all(&store).unwrap();
+        // Every entry is bigger than half the limit, so each one ends up in its own file.
+        let config = AuditConfig {
+            max_file_size_bytes: 100,
+            max_files: 2,
+            ..AuditConfig::default()
+        };
+        let log = AuditLog::new(&config, &store);
+        for i in 0..5 {
+            let mut entry =
+                AuditEntry::new(AuditAction::Archive, format!("@user{}:example.com", i));
+            entry.time = 1_000 + i;
+            log.record(entry);
+        }
+
+        let actors = |entries: Vec<AuditEntry>| -> Vec<String> {
+            entries.into_iter().map(|entry| entry.actor).collect()
+        };
+        let all = log.query(&AuditFilter::default(), 10);
+        let latest = log.query(&AuditFilter::default(), 2);
+        let filtered = log.query(
+            &AuditFilter {
+                until: Some(1_004),
+                ..AuditFilter::default()
+            },
+            10,
+        );
+        let files_left = (
+            store.join("audit.jsonl.2").exists(),
+            store.join("audit.jsonl.3").exists(),
+        );
+        fs::remove_dir_all(&store).unwrap();
+
+        // The oldest entries were dropped with the oldest rotated file.
+        assert_eq!(
+            actors(all),
+            vec![
+                "@user2:example.com",
+                "@user3:example.com",
+                "@user4:example.com"
+            ]
+        );
+        assert_eq!(
+            actors(latest),
+            vec!["@user3:example.com", "@user4:example.com"]
+        );
+        assert_eq!(
+            actors(filtered),
+            vec!["@user2:example.com", "@user3:example.com"]
+        );
+        assert_eq!(files_left, (true, false));
+    }
+}
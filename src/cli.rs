@@ -0,0 +1,204 @@
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::{env, fs};
+
+use structopt::StructOpt;
+
+use crate::config::Config;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "matrix-ipfs-bot",
+    about = "A bot for Matrix that converts Matrix events to IPFS files"
+)]
+pub struct Cli {
+    /// Path of the config file
+    #[structopt(long, default_value = "./config.yml", parse(from_os_str))]
+    pub config: PathBuf,
+
+    /// Directory for the session and the encryption store (defaults to `store_path` or ~/ipfs_bot)
+    #[structopt(long, parse(from_os_str))]
+    pub store: Option<PathBuf>,
+
+    /// Read the passphrase of the encryption store from this file
+    #[structopt(long, parse(from_os_str))]
+    pub store_passphrase_file: Option<PathBuf>,
+
+    /// Read the passphrase of the encryption store from this environment variable
+    #[structopt(long, default_value = "IPFS_BOT_STORE_PASSPHRASE")]
+    pub store_passphrase_env: String,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    /// The environment variables the CLI reads secrets from.
+    pub fn env_vars(&self) -> Vec<String> {
+        let mut vars = vec![self.store_passphrase_env.clone()];
+        match &self.command {
+            Command::Run(args) | Command::Login(args) => {
+                vars.extend(args.password.password_env.clone());
+                vars.extend(args.access_token.access_token_env.clone());
+            }
+            Command::Logout | Command::CheckConfig => {}
+        }
+        vars
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Log in if needed and start the bot
+    Run(LoginArgs),
+    /// Log in and store the session without starting the bot
+    Login(LoginArgs),
+    /// Invalidate the stored session and delete it
+    Logout,
+    /// Load and validate the config and print it with secrets redacted
+    CheckConfig,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct LoginArgs {
+    /// URL of the homeserver, e.g. https://matrix.org
+    #[structopt(long)]
+    pub homeserver: Option<String>,
+
+    /// Username or full user ID of the bot
+    #[structopt(long)]
+    pub user: Option<String>,
+
+    /// Device ID belonging to the access token
+    #[structopt(long)]
+    pub device_id: Option<String>,
+
+    #[structopt(flatten)]
+    pub password: PasswordSource,
+
+    #[structopt(flatten)]
+    pub access_token: AccessTokenSource,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct PasswordSource {
+    /// Read the password from this file
+    #[structopt(long, parse(from_os_str))]
+    pub password_file: Option<PathBuf>,
+
+    /// Read the password from this environment variable
+    #[structopt(long)]
+    pub password_env: Option<String>,
+
+    /// Read the password from stdin
+    #[structopt(long)]
+    pub password_stdin: bool,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct AccessTokenSource {
+    /// Read an access token from this file instead of logging in with a password
+    #[structopt(long, parse(from_os_str))]
+    pub access_token_file: Option<PathBuf>,
+
+    /// Read an access token from this environment variable
+    #[structopt(long)]
+    pub access_token_env: Option<String>,
+
+    /// Read an access token from stdin
+    #[structopt(long)]
+    pub access_token_stdin: bool,
+}
+
+/// How the bot should authenticate if there is no stored session.
+pub enum Credentials {
+    Password(String),
+    AccessToken(String),
+}
+
+/// Reads a secret from the first source that is set.
+fn read_secret(
+    file: &Option<PathBuf>,
+    env_var: &Option<String>,
+    stdin: bool,
+) -> Result<Option<String>, String> {
+    if let Some(path) = file {
+        let secret = fs::read_to_string(path)
+            .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        return Ok(Some(secret.trim().to_string()));
+    }
+    if let Some(name) = env_var {
+        let secret =
+            env::var(name).map_err(|_| format!("environment variable {} is not set", name))?;
+        return Ok(Some(secret));
+    }
+    if stdin {
+        let mut secret = String::new();
+        io::stdin()
+            .lock()
+            .read_line(&mut secret)
+            .map_err(|e| format!("unable to read from stdin: {}", e))?;
+        return Ok(Some(secret.trim().to_string()));
+    }
+    Ok(None)
+}
+
+impl LoginArgs {
+    /// Fills everything not given on the command line from the config.
+    pub fn apply_config(&mut self, config: &Config) {
+        let credentials = &config.credentials;
+        if self.homeserver.is_none() {
+            self.homeserver = config.homeserver.clone();
+        }
+        if self.user.is_none() {
+            self.user = credentials.user.clone();
+        }
+        if self.device_id.is_none() {
+            self.device_id = credentials.device_id.clone();
+        }
+
+        let password = &mut self.password;
+        if password.password_file.is_none()
+            && password.password_env.is_none()
+            && !password.password_stdin
+        {
+            password.password_file = credentials.password_file.clone();
+            password.password_env = credentials.password_env.clone();
+        }
+
+        let access_token = &mut self.access_token;
+        if access_token.access_token_file.is_none()
+            && access_token.access_token_env.is_none()
+            && !access_token.access_token_stdin
+        {
+            access_token.access_token_file = credentials.access_token_file.clone();
+            access_token.access_token_env = credentials.access_token_env.clone();
+        }
+    }
+
+    pub fn credentials(&self) -> Result<Option<Credentials>, String> {
+        let token = read_secret(
+            &self.access_token.access_token_file,
+            &self.access_token.access_token_env,
+            self.access_token.access_token_stdin,
+        )?;
+        if let Some(token) = token {
+            return Ok(Some(Credentials::AccessToken(token)));
+        }
+        let password = read_secret(
+            &self.password.password_file,
+            &self.password.password_env,
+            self.password.password_stdin,
+        )?;
+        Ok(password.map(Credentials::Password))
+    }
+}
+
+impl Cli {
+    pub fn store_passphrase(&self) -> Result<Option<String>, String> {
+        if self.store_passphrase_file.is_some() {
+            return read_secret(&self.store_passphrase_file, &None, false);
+        }
+        Ok(env::var(&self.store_passphrase_env).ok())
+    }
+}
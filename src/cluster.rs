@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::IpfsApiAuth;
+use crate::errors::Error;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// URL of the REST API of an IPFS Cluster peer, e.g. `http://127.0.0.1:9094`.
+    /// If set files are pinned through the cluster instead of on the node directly.
+    pub api: Option<String>,
+    pub auth: Option<IpfsApiAuth>,
+    /// Minimum number of peers that have to pin a file, the cluster default if unset.
+    pub replication_factor_min: Option<i32>,
+    /// Maximum number of peers that pin a file, the cluster default if unset.
+    pub replication_factor_max: Option<i32>,
+    /// Name of the pin, `{room}` and `{filename}` are replaced.
+    pub name_template: String,
+    /// How often the pin status across peers is checked in seconds.
+    pub poll_interval_secs: u64,
+    /// How long to wait for the peers to finish pinning in seconds.
+    pub timeout_secs: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            api: None,
+            auth: None,
+            replication_factor_min: None,
+            replication_factor_max: None,
+            name_template: "{room}/{filename}".to_string(),
+            poll_interval_secs: 2,
+            timeout_secs: 2 * 60,
+        }
+    }
+}
+
+impl ClusterConfig {
+    pub fn pin_name(&self, room: &str, filename: &str) -> String {
+        self.name_template
+            .replace("{room}", room)
+            .replace("{filename}", filename)
+    }
+}
+
+/// How a pin is doing across the cluster peers it is allocated to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterPin {
+    /// Peers the cluster allocated the pin to.
+    pub allocated: usize,
+    pub pinned: usize,
+    /// Errors of the peers that failed, by peer name.
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+impl ClusterPin {
+    /// Every allocated peer pinned it or failed.
+    pub fn is_settled(&self) -> bool {
+        self.allocated > 0 && self.pinned + self.errors.len() >= self.allocated
+    }
+}
+
+/// The parts of the `GlobalPinInfo` of the cluster the bot cares about.
+#[derive(Debug, Deserialize)]
+struct GlobalPinInfo {
+    #[serde(default)]
+    peer_map: HashMap<String, PeerPinInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerPinInfo {
+    #[serde(default)]
+    peername: String,
+    status: String,
+    #[serde(default)]
+    error: String,
+}
+
+impl From<GlobalPinInfo> for ClusterPin {
+    fn from(info: GlobalPinInfo) -> Self {
+        // Peers the pin isn't allocated to report `remote`.
+        let allocated: Vec<&PeerPinInfo> = info
+            .peer_map
+            .values()
+            .filter(|peer| peer.status != "remote")
+            .collect();
+        Self {
+            allocated: allocated.len(),
+            pinned: allocated.iter().filter(|p| p.status == "pinned").count(),
+            errors: allocated
+                .iter()
+                .filter(|p| p.status.ends_with("error"))
+                .map(|p| format!("{}: {}", p.peername, p.error))
+                .collect(),
+        }
+    }
+}
+
+fn request(
+    http_client: &reqwest::Client,
+    config: &ClusterConfig,
+    method: reqwest::Method,
+    cid: &str,
+) -> Result<reqwest::RequestBuilder, Error> {
+    let api = config
+        .api
+        .as_ref()
+        .ok_or_else(|| Error::Cluster("no cluster configured".to_string()))?;
+    let url = format!("{}/pins/{}", api.trim_end_matches('/'), cid);
+    let request = http_client.request(method, &url);
+    Ok(match &config.auth {
+        Some(IpfsApiAuth::Basic { username, password }) => {
+            request.basic_auth(username, Some(password))
+        }
+        Some(IpfsApiAuth::Bearer { token }) => request.bearer_auth(token),
+        None => request,
+    })
+}
+
+fn cluster_error(e: reqwest::Error) -> Error {
+    Error::Cluster(e.to_string())
+}
+
+/// Asks the cluster to pin `cid` and nothing more, so it can be retried.
+pub async fn pin(
+    http_client: &reqwest::Client,
+    config: &ClusterConfig,
+    cid: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let mut query = vec![("name", name.to_string())];
+    if let Some(min) = config.replication_factor_min {
+        query.push(("replication-min", min.to_string()));
+    }
+    if let Some(max) = config.replication_factor_max {
+        query.push(("replication-max", max.to_string()));
+    }
+    request(http_client, config, reqwest::Method::POST, cid)?
+        .query(&query)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(cluster_error)?;
+    Ok(())
+}
+
+pub async fn status(
+    http_client: &reqwest::Client,
+    config: &ClusterConfig,
+    cid: &str,
+) -> Result<ClusterPin, Error> {
+    let info: GlobalPinInfo = request(http_client, config, reqwest::Method::GET, cid)?
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(cluster_error)?
+        .json()
+        .await
+        .map_err(cluster_error)?;
+    Ok(info.into())
+}
+
+/// Polls the status of `cid` until every allocated peer pinned it or failed,
+/// or the timeout ran out.
+pub async fn wait_for_peers(
+    http_client: &reqwest::Client,
+    config: &ClusterConfig,
+    cid: &str,
+) -> Result<ClusterPin, Error> {
+    let deadline = Instant::now() + Duration::from_secs(config.timeout_secs);
+    loop {
+        let pin = status(http_client, config, cid).await?;
+        if pin.is_settled() || Instant::now() >= deadline {
+            return Ok(pin);
+        }
+        tokio::time::delay_for(Duration::from_secs(config.poll_interval_secs.max(1))).await;
+    }
+}
+
+pub async fn unpin(
+    http_client: &reqwest::Client,
+    config: &ClusterConfig,
+    cid: &str,
+) -> Result<(), Error> {
+    request(http_client, config, reqwest::Method::DELETE, cid)?
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(cluster_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster_pin(status: serde_json::Value) -> ClusterPin {
+        serde_json::from_value::<GlobalPinInfo>(status)
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn counts_only_allocated_peers() {
+        let pin = cluster_pin(serde_json::json!({
+            "cid": "QmHash",
+            "peer_map": {
+                "a": { "peername": "a", "status": "pinned" },
+                "b": { "peername": "b", "status": "pinning" },
+                "c": { "peername": "c", "status": "remote" },
+                "d": { "peername": "d", "status": "pin_error", "error": "disk full" },
+            },
+        }));
+        assert_eq!(pin.allocated, 3);
+        assert_eq!(pin.pinned, 1);
+        assert_eq!(pin.errors, vec!["d: disk full"]);
+        assert!(!pin.is_settled());
+    }
+
+    #[test]
+    fn settles_once_every_allocated_peer_answered() {
+        let pin = cluster_pin(serde_json::json!({
+            "peer_map": {
+                "a": { "peername": "a", "status": "pinned" },
+                "b": { "peername": "b", "status": "remote" },
+            },
+        }));
+        assert_eq!((pin.allocated, pin.pinned), (1, 1));
+        assert!(pin.is_settled());
+
+        // Without any allocation the cluster hasn't decided yet.
+        let pin = cluster_pin(serde_json::json!({ "cid": "QmHash" }));
+        assert_eq!(pin.allocated, 0);
+        assert!(!pin.is_settled());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::add::AddOptions;
+use crate::audit::AuditFilter;
+use crate::permissions::Permission;
+
+/// Prefix all commands start with.
+pub const PREFIX: &str = "!ipfs";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigCommand {
+    /// Show one setting or, without a key, all of them.
+    Get(Option<String>),
+    Set(String, String),
+}
+
+/// Commands only accepted from bot admins in the admin room.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminCommand {
+    Stats,
+    Rooms,
+    Leave(String),
+    BanUser(String),
+    Gc,
+    RepinAll,
+    Reload,
+    /// Answer a SAS verification of the bot's device.
+    Verify {
+        flow_id: String,
+        action: VerifyAction,
+    },
+    /// Show the latest audit log entries matching the filter.
+    Audit(AuditFilter),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyAction {
+    Accept,
+    Confirm,
+    Cancel,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BotCommand {
+    /// Archive the media the command replies to, with `ipfs add` flags given in the command.
+    Archive(AddOptions),
+    Config(ConfigCommand),
+    Admin(AdminCommand),
+    /// Show the CID of the MFS directory of the room.
+    RoomRoot,
+    /// Fetch an encrypted upload from IPFS and post it decrypted.
+    Get {
+        hash: String,
+        key: String,
+        filename: Option<String>,
+    },
+    /// The sender of some media answering whether it may be archived.
+    Consent(bool),
+    /// A subcommand that exists but was used wrongly, with the usage to show.
+    Usage(&'static str),
+}
+
+impl BotCommand {
+    /// The permission needed to run the command. Who may archive is configurable,
+    /// so the caller decides for `Archive`.
+    pub fn required_permission(&self) -> Permission {
+        match self {
+            BotCommand::Archive(_) | BotCommand::RoomRoot | BotCommand::Usage(_) => {
+                Permission::Anyone
+            }
+            // Only answers of the media sender count, which is checked when answering.
+            BotCommand::Consent(_) => Permission::Anyone,
+            // Whoever has the key may read the file anyway.
+            BotCommand::Get { .. } => Permission::Anyone,
+            BotCommand::Config(ConfigCommand::Get(_)) => Permission::Anyone,
+            BotCommand::Config(ConfigCommand::Set(_, _)) => Permission::Moderator,
+            BotCommand::Admin(_) => Permission::Admin,
+        }
+    }
+}
+
+const CONFIG_USAGE: &str = "Usage: !ipfs config get [key] | !ipfs config set <key> <value|unset>";
+const ARCHIVE_USAGE: &str = "Usage: !ipfs [--cid-version=<0|1>] [--raw-leaves] [--chunker=<size-<bytes>|rabin>] [--hash=<function>] [--only-hash] [--trickle]";
+const GET_USAGE: &str = "Usage: !ipfs get <hash> <key> [filename]";
+const ADMIN_USAGE: &str =
+    "Usage: !ipfs admin stats | rooms | leave <room> | ban-user <mxid> | gc | repin-all | reload | verify <flow id> accept|confirm|cancel | audit [user=<mxid>] [room=<room>] [since=<YYYY-MM-DD>] [until=<YYYY-MM-DD>]";
+
+/// Finds the command in a message body, skipping the quoted fallback of replies.
+pub fn parse(body: &str) -> Option<BotCommand> {
+    let line = body
+        .lines()
+        .filter(|line| !line.starts_with('>'))
+        .map(str::trim)
+        .find(|line| line.starts_with(PREFIX));
+
+    let line = match line {
+        Some(line) => line,
+        // Older versions reacted to the prefix anywhere in the message.
+        None if body.contains(PREFIX) => return Some(BotCommand::Archive(AddOptions::default())),
+        None => return None,
+    };
+
+    let mut args = line[PREFIX.len()..].split_whitespace();
+    let command = match args.next() {
+        Some("config") => match (args.next(), args.next()) {
+            (Some("get"), key) => BotCommand::Config(ConfigCommand::Get(key.map(String::from))),
+            (Some("set"), Some(key)) => {
+                let value: Vec<&str> = args.collect();
+                if value.is_empty() {
+                    BotCommand::Usage(CONFIG_USAGE)
+                } else {
+                    BotCommand::Config(ConfigCommand::Set(key.to_string(), value.join(" ")))
+                }
+            }
+            _ => BotCommand::Usage(CONFIG_USAGE),
+        },
+        Some("get") => match (args.next(), args.next()) {
+            (Some(hash), Some(key)) => BotCommand::Get {
+                hash: hash.to_string(),
+                key: key.to_string(),
+                filename: args.next().map(String::from),
+            },
+            _ => BotCommand::Usage(GET_USAGE),
+        },
+        Some("room-root") => BotCommand::RoomRoot,
+        Some("approve") => BotCommand::Consent(true),
+        Some("deny") => BotCommand::Consent(false),
+        Some("admin") => match (args.next(), args.next()) {
+            (Some("stats"), None) => BotCommand::Admin(AdminCommand::Stats),
+            (Some("rooms"), None) => BotCommand::Admin(AdminCommand::Rooms),
+            (Some("leave"), Some(room)) => BotCommand::Admin(AdminCommand::Leave(room.to_string())),
+            (Some("ban-user"), Some(user)) => {
+                BotCommand::Admin(AdminCommand::BanUser(user.to_string()))
+            }
+            (Some("gc"), None) => BotCommand::Admin(AdminCommand::Gc),
+            (Some("repin-all"), None) => BotCommand::Admin(AdminCommand::RepinAll),
+            (Some("reload"), None) => BotCommand::Admin(AdminCommand::Reload),
+            (Some("audit"), first) => match AuditFilter::parse(first.into_iter().chain(args)) {
+                Ok(filter) => BotCommand::Admin(AdminCommand::Audit(filter)),
+                Err(_) => BotCommand::Usage(ADMIN_USAGE),
+            },
+            (Some("verify"), Some(flow_id)) => {
+                let action = match args.next() {
+                    Some("accept") => Some(VerifyAction::Accept),
+                    Some("confirm") => Some(VerifyAction::Confirm),
+                    Some("cancel") => Some(VerifyAction::Cancel),
+                    _ => None,
+                };
+                match action {
+                    Some(action) => BotCommand::Admin(AdminCommand::Verify {
+                        flow_id: flow_id.to_string(),
+                        action,
+                    }),
+                    None => BotCommand::Usage(ADMIN_USAGE),
+                }
+            }
+            _ => BotCommand::Usage(ADMIN_USAGE),
+        },
+        Some(flag) if flag.starts_with("--") => {
+            match AddOptions::parse_flags(std::iter::once(flag).chain(args)) {
+                Ok(options) => BotCommand::Archive(options),
+                Err(_) => BotCommand::Usage(ARCHIVE_USAGE),
+            }
+        }
+        _ => BotCommand::Archive(AddOptions::default()),
+    };
+    Some(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_messages_without_the_prefix() {
+        assert_eq!(parse("hello"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn skips_the_quoted_fallback_of_replies() {
+        let body = "> <@alice:example.com> !ipfs config get\n\n!ipfs";
+        assert_eq!(
+            parse(body),
+            Some(BotCommand::Archive(AddOptions::default()))
+        );
+        // Older versions reacted to the prefix anywhere in the message.
+        assert_eq!(
+            parse("please !ipfs this"),
+            Some(BotCommand::Archive(AddOptions::default()))
+        );
+    }
+
+    #[test]
+    fn parses_config_commands() {
+        assert_eq!(
+            parse("!ipfs config get"),
+            Some(BotCommand::Config(ConfigCommand::Get(None)))
+        );
+        assert_eq!(
+            parse("!ipfs config get reply_style"),
+            Some(BotCommand::Config(ConfigCommand::Get(Some(
+                "reply_style".to_string()
+            ))))
+        );
+        assert_eq!(
+            parse("!ipfs config set allowed_types [image, video]"),
+            Some(BotCommand::Config(ConfigCommand::Set(
+                "allowed_types".to_string(),
+                "[image, video]".to_string()
+            )))
+        );
+        assert_eq!(
+            parse("!ipfs config set reply_style"),
+            Some(BotCommand::Usage(CONFIG_USAGE))
+        );
+        assert_eq!(parse("!ipfs config"), Some(BotCommand::Usage(CONFIG_USAGE)));
+    }
+
+    #[test]
+    fn parses_admin_commands() {
+        assert_eq!(
+            parse("!ipfs admin stats"),
+            Some(BotCommand::Admin(AdminCommand::Stats))
+        );
+        assert_eq!(
+            parse("!ipfs admin leave !room:example.com"),
+            Some(BotCommand::Admin(AdminCommand::Leave(
+                "!room:example.com".to_string()
+            )))
+        );
+        assert_eq!(
+            parse("!ipfs admin ban-user @spam:example.com"),
+            Some(BotCommand::Admin(AdminCommand::BanUser(
+                "@spam:example.com".to_string()
+            )))
+        );
+        assert_eq!(
+            parse("!ipfs admin repin-all"),
+            Some(BotCommand::Admin(AdminCommand::RepinAll))
+        );
+        assert_eq!(
+            parse("!ipfs admin verify abc confirm"),
+            Some(BotCommand::Admin(AdminCommand::Verify {
+                flow_id: "abc".to_string(),
+                action: VerifyAction::Confirm,
+            }))
+        );
+        assert_eq!(
+            parse("!ipfs admin verify abc"),
+            Some(BotCommand::Usage(ADMIN_USAGE))
+        );
+        assert_eq!(
+            parse("!ipfs admin stats now"),
+            Some(BotCommand::Usage(ADMIN_USAGE))
+        );
+        assert_eq!(parse("!ipfs admin"), Some(BotCommand::Usage(ADMIN_USAGE)));
+    }
+
+    #[test]
+    fn admin_commands_need_admins() {
+        let command = parse("!ipfs admin gc").unwrap();
+        assert_eq!(command, BotCommand::Admin(AdminCommand::Gc));
+        assert_eq!(command.required_permission(), Permission::Admin);
+    }
+
+    #[test]
+    fn parses_add_flags_of_archive_commands() {
+        let only_hash = AddOptions {
+            only_hash: Some(true),
+            ..AddOptions::default()
+        };
+        assert_eq!(
+            parse("!ipfs --only-hash"),
+            Some(BotCommand::Archive(only_hash))
+        );
+        assert_eq!(
+            parse("!ipfs --pin=false"),
+            Some(BotCommand::Usage(ARCHIVE_USAGE))
+        );
+    }
+}
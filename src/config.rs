@@ -1,16 +1,507 @@
+use matrix_sdk::identifiers::{RoomId, UserId};
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::convert::TryFrom;
+use std::env;
 use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use tracing_subscriber::EnvFilter;
+use url::Url;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+use crate::add::AddOptions;
+use crate::admin::AdminConfig;
+use crate::audit::AuditConfig;
+use crate::cluster::ClusterConfig;
+use crate::consent::ConsentConfig;
+use crate::e2ee::E2eeConfig;
+use crate::encrypt::EncryptionConfig;
+use crate::health::HealthConfig;
+use crate::logging::LoggingConfig;
+use crate::metrics::MetricsConfig;
+use crate::mfs::MfsConfig;
+use crate::nodes::{IpfsNodeConfig, NodeRole, ReplicationConfig};
+use crate::permissions::Permission;
+use crate::progress::ProgressConfig;
+use crate::reactions::ReactionsConfig;
+use crate::reload::ReloadConfig;
+use crate::remote_pin::RemotePinningConfig;
+use crate::retry::RetryPolicy;
+use crate::shutdown::ShutdownConfig;
+use crate::sync::SyncConfig;
+
+/// The config format version this build understands.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Environment variables starting with this prefix override config values.
+/// Nested keys are separated by `__`, e.g. `IPFS_BOT_LIMITS__MAX_FILE_SIZE_BYTES`.
+const ENV_PREFIX: &str = "IPFS_BOT_";
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    pub ipfs_gateway: String,
+    /// Version of the config format.
+    #[serde(default = "default_version")]
+    pub version: u32,
+    /// URL of the homeserver, used if neither the CLI nor the stored session provide one.
+    #[serde(default)]
+    pub homeserver: Option<String>,
+    /// Where to get the credentials from if there is no stored session yet.
+    #[serde(default)]
+    pub credentials: CredentialsConfig,
+    /// Directory for the session and the encryption store.
+    #[serde(default)]
+    pub store_path: Option<PathBuf>,
+    /// Deprecated single gateway, use `ipfs_gateways`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipfs_gateway: Option<String>,
+    /// Gateways used for the links. The first one is the default.
+    #[serde(default)]
+    pub ipfs_gateways: Vec<String>,
     pub ipfs_api: String,
+    /// Credentials for an IPFS API behind a reverse proxy.
+    #[serde(default)]
+    pub ipfs_api_auth: Option<IpfsApiAuth>,
+    /// More IPFS nodes next to `ipfs_api`, which is always the first primary.
+    #[serde(default)]
+    pub ipfs_nodes: Vec<IpfsNodeConfig>,
+    /// How many replica nodes pin each file.
+    #[serde(default)]
+    pub replication: ReplicationConfig,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub policies: Policies,
+    /// Retry policy for the media download and the IPFS `add`/`pin` calls.
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// "Working on it" notices that get edited while a job runs.
+    #[serde(default)]
+    pub progress: ProgressConfig,
+    /// Emoji reactions on the command message showing the job state.
+    #[serde(default)]
+    pub reactions: ReactionsConfig,
+    /// Reloading the config without a restart.
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    /// Admin room, admin users and the alerts posted there.
+    #[serde(default)]
+    pub admin: AdminConfig,
+    /// Asking the sender of the media before archiving it for somebody else.
+    #[serde(default)]
+    pub consent: ConsentConfig,
+    /// Encrypting files before they get published on IPFS.
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    /// Device verification and room keys for encrypted rooms.
+    #[serde(default)]
+    pub e2ee: E2eeConfig,
+    /// Waiting for running jobs on SIGINT and SIGTERM.
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    /// Handling of commands sent while the bot was not running.
+    #[serde(default)]
+    pub sync: SyncConfig,
+    /// Prometheus metrics endpoint.
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    /// Health and readiness endpoints.
+    #[serde(default)]
+    pub health: HealthConfig,
+    /// Log level and format.
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Audit trail of archive and unpin actions.
+    #[serde(default)]
+    pub audit: AuditConfig,
+    /// Remote pinning service used next to the local node.
+    #[serde(default)]
+    pub remote_pinning: RemotePinningConfig,
+    /// Pinning through an IPFS Cluster instead of on the node.
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+    /// Default options of `ipfs add`, commands may override them.
+    #[serde(default)]
+    pub add: AddOptions,
+    /// Copies of archived files in the Mutable File System, organized by room.
+    #[serde(default)]
+    pub mfs: MfsConfig,
+}
+
+fn default_version() -> u32 {
+    CONFIG_VERSION
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    /// Username or full user ID of the bot.
+    pub user: Option<String>,
+    /// Device ID belonging to the access token.
+    pub device_id: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub password_env: Option<String>,
+    pub access_token_file: Option<PathBuf>,
+    pub access_token_env: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum IpfsApiAuth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+impl IpfsApiAuth {
+    fn redacted(self) -> Self {
+        match self {
+            IpfsApiAuth::Basic { username, .. } => IpfsApiAuth::Basic {
+                username,
+                password: REDACTED.to_string(),
+            },
+            IpfsApiAuth::Bearer { .. } => IpfsApiAuth::Bearer {
+                token: REDACTED.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Media bigger than this is refused.
+    pub max_file_size_bytes: Option<u64>,
+    /// Timeout for downloading a single file from the media repository.
+    pub download_timeout_secs: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_file_size_bytes: None,
+            download_timeout_secs: 300,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaType {
+    Image,
+    Video,
+    File,
+    Audio,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policies {
+    /// Join rooms the bot gets invited to.
+    pub auto_join: bool,
+    /// Rooms the bot joins and answers in. Empty means all rooms.
+    pub allowed_rooms: Vec<String>,
+    /// Users that may use the bot. Empty means everyone.
+    pub allowed_users: Vec<String>,
+    /// Media types that get archived.
+    pub allowed_types: Vec<MediaType>,
+    /// Who may trigger `!ipfs` unless a room overrides it.
+    pub archive_permission: Permission,
+    /// Power level that makes a member a moderator for the bot.
+    pub moderator_power_level: i64,
+}
+
+impl Default for Policies {
+    fn default() -> Self {
+        Self {
+            auto_join: true,
+            allowed_rooms: Vec::new(),
+            allowed_users: Vec::new(),
+            allowed_types: vec![
+                MediaType::Image,
+                MediaType::Video,
+                MediaType::File,
+                MediaType::Audio,
+            ],
+            archive_permission: Permission::Anyone,
+            moderator_power_level: 50,
+        }
+    }
+}
+
+impl Policies {
+    pub fn room_allowed(&self, room_id: &str) -> bool {
+        self.allowed_rooms.is_empty() || self.allowed_rooms.iter().any(|r| r == room_id)
+    }
+
+    pub fn user_allowed(&self, user_id: &str) -> bool {
+        self.allowed_users.is_empty() || self.allowed_users.iter().any(|u| u == user_id)
+    }
+}
+
+/// The config overrides among the environment `vars`, sorted by key. `excluded` are
+/// variables the CLI reads secrets from, which may share the prefix.
+fn env_overrides(
+    vars: impl Iterator<Item = (String, String)>,
+    excluded: &[String],
+) -> Vec<(String, String)> {
+    let mut overrides: Vec<(String, String)> = vars
+        .filter(|(key, _)| key.starts_with(ENV_PREFIX))
+        .filter(|(key, _)| !excluded.contains(key))
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+/// Turns the value of an environment variable into YAML. Quoted values are parsed as YAML.
+/// Other values keep the type of the value they replace. If that is unknown, numbers,
+/// booleans, lists and maps are parsed and the rest stays a string, so e.g.
+/// `!room:example.com` isn't read as a YAML tag.
+fn override_value(value: &str, current: Option<&Value>) -> Result<Value, serde_yaml::Error> {
+    let quoted = value.starts_with(|c: char| c == '"' || c == '\'');
+    match current {
+        _ if quoted => serde_yaml::from_str(value),
+        Some(Value::String(_)) => Ok(Value::String(value.to_string())),
+        Some(Value::Null) | None => Ok(match serde_yaml::from_str(value) {
+            Ok(parsed @ Value::Bool(_))
+            | Ok(parsed @ Value::Number(_))
+            | Ok(parsed @ Value::Sequence(_))
+            | Ok(parsed @ Value::Mapping(_)) => parsed,
+            _ if value == "null" || value == "~" => Value::Null,
+            _ => Value::String(value.to_string()),
+        }),
+        Some(_) => serde_yaml::from_str(value),
+    }
+}
+
+/// Sets `value` at the `__` separated, lowercased `key` path inside `root`.
+fn apply_override(root: &mut Value, key: &str, value: &str) -> Result<(), String> {
+    let path: Vec<String> = key.split("__").map(|k| k.to_lowercase()).collect();
+
+    let mut current = root;
+    for (i, segment) in path.iter().enumerate() {
+        if !current.is_mapping() {
+            *current = Value::Mapping(Mapping::new());
+        }
+        let mapping = current.as_mapping_mut().unwrap();
+        let segment = Value::String(segment.clone());
+        if i == path.len() - 1 {
+            let value = override_value(value, mapping.get(&segment))
+                .map_err(|e| format!("invalid value for {}{}: {}", ENV_PREFIX, key, e))?;
+            mapping.insert(segment, value);
+            return Ok(());
+        }
+        if !mapping.contains_key(&segment) {
+            mapping.insert(segment.clone(), Value::Mapping(Mapping::new()));
+        }
+        current = mapping.get_mut(&segment).unwrap();
+    }
+    Ok(())
+}
+
+fn validate_url(field: &str, url: &str) -> Result<(), String> {
+    match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(()),
+        Ok(parsed) => Err(format!(
+            "{}: unsupported scheme '{}' in '{}'",
+            field,
+            parsed.scheme(),
+            url
+        )),
+        Err(e) => Err(format!("{}: invalid URL '{}': {}", field, url, e)),
+    }
 }
 
 impl Config {
-    pub fn load() -> Self {
-        let f = OpenOptions::new().read(true).open("./config.yml").unwrap();
-        let json: Self = serde_yaml::from_reader(f).expect("config should be proper YAML");
-        json
+    /// Loads the config file with the environment overrides applied.
+    /// `cli_env_vars` are the variables the CLI reads secrets from.
+    pub fn load(path: &Path, cli_env_vars: &[String]) -> Result<Self, String> {
+        let f = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(|e| format!("unable to open {}: {}", path.display(), e))?;
+        let mut raw: Value = serde_yaml::from_reader(f)
+            .map_err(|e| format!("{} is not proper YAML: {}", path.display(), e))?;
+
+        // The credentials in the file may name variables with the prefix as well.
+        let mut excluded = cli_env_vars.to_vec();
+        for name in &["password_env", "access_token_env"] {
+            let var = raw
+                .get("credentials")
+                .and_then(|credentials| credentials.get(*name))
+                .and_then(Value::as_str);
+            excluded.extend(var.map(String::from));
+        }
+        for (key, value) in env_overrides(env::vars(), &excluded) {
+            apply_override(&mut raw, &key[ENV_PREFIX.len()..], &value)?;
+        }
+
+        let mut config: Self = serde_yaml::from_value(raw)
+            .map_err(|e| format!("invalid config in {}: {}", path.display(), e))?;
+        if let Some(gateway) = config.ipfs_gateway.take() {
+            config.ipfs_gateways.insert(0, gateway);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version != CONFIG_VERSION {
+            return Err(format!(
+                "version: unsupported config version {}, expected {}",
+                self.version, CONFIG_VERSION
+            ));
+        }
+        if let Some(homeserver) = &self.homeserver {
+            validate_url("homeserver", homeserver)?;
+        }
+        if self.ipfs_gateways.is_empty() {
+            return Err("ipfs_gateways: at least one gateway is required".to_string());
+        }
+        for (i, gateway) in self.ipfs_gateways.iter().enumerate() {
+            validate_url(&format!("ipfs_gateways[{}]", i), gateway)?;
+        }
+        validate_url("ipfs_api", &self.ipfs_api)?;
+        for (i, node) in self.ipfs_nodes.iter().enumerate() {
+            validate_url(&format!("ipfs_nodes[{}].api", i), &node.api)?;
+        }
+        let replica_nodes = self
+            .ipfs_nodes
+            .iter()
+            .filter(|node| node.role == NodeRole::Replica)
+            .count();
+        if let Some(replicas) = self.replication.replicas {
+            if replicas > replica_nodes {
+                return Err(format!(
+                    "replication.replicas: only {} replica node(s) are configured",
+                    replica_nodes
+                ));
+            }
+        }
+        // Files are encrypted in memory.
+        if self.encryption.enabled && self.limits.max_file_size_bytes.is_none() {
+            return Err(
+                "encryption.enabled: requires limits.max_file_size_bytes to be set".to_string(),
+            );
+        }
+        if self.retry.max_attempts == 0 {
+            return Err("retry.max_attempts: has to be at least 1".to_string());
+        }
+        if self.retry.jitter < 0.0 || self.retry.jitter > 1.0 {
+            return Err("retry.jitter: has to be between 0.0 and 1.0".to_string());
+        }
+        if let Some(room) = &self.admin.room {
+            RoomId::try_from(room.as_str())
+                .map_err(|e| format!("admin.room: invalid room ID '{}': {}", room, e))?;
+        }
+        for user in &self.admin.users {
+            UserId::try_from(user.as_str())
+                .map_err(|e| format!("admin.users: invalid user ID '{}': {}", user, e))?;
+        }
+        if self.admin.repo_usage_alert_percent > 100 {
+            return Err("admin.repo_usage_alert_percent: has to be at most 100".to_string());
+        }
+        if let Some(endpoint) = &self.remote_pinning.endpoint {
+            validate_url("remote_pinning.endpoint", endpoint)?;
+        }
+        if let Some(api) = &self.cluster.api {
+            validate_url("cluster.api", api)?;
+        }
+        self.add.validate().map_err(|e| format!("add.{}", e))?;
+        self.mfs.validate().map_err(|e| format!("mfs.{}", e))?;
+        EnvFilter::try_new(&self.logging.level)
+            .map_err(|e| format!("logging.level: invalid directives: {}", e))?;
+        Ok(())
+    }
+
+    /// The gateway used for links unless a room prefers another one.
+    pub fn default_gateway(&self) -> &str {
+        &self.ipfs_gateways[0]
+    }
+
+    /// A copy of the config that is safe to print.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.ipfs_api_auth = config.ipfs_api_auth.map(IpfsApiAuth::redacted);
+        config.cluster.auth = config.cluster.auth.map(IpfsApiAuth::redacted);
+        if config.remote_pinning.token.is_some() {
+            config.remote_pinning.token = Some(REDACTED.to_string());
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn room_ids_stay_strings() {
+        let mut raw = yaml("admin: {}");
+        apply_override(&mut raw, "ADMIN__ROOM", "!room:example.com").unwrap();
+        assert_eq!(raw, yaml("admin:\n  room: \"!room:example.com\""));
+    }
+
+    #[test]
+    fn values_keep_the_type_they_replace() {
+        let mut raw =
+            yaml("remote_pinning:\n  token: \"abc\"\nlimits:\n  download_timeout_secs: 300");
+        apply_override(&mut raw, "REMOTE_PINNING__TOKEN", "12345").unwrap();
+        apply_override(&mut raw, "LIMITS__DOWNLOAD_TIMEOUT_SECS", "60").unwrap();
+        assert_eq!(
+            raw,
+            yaml("remote_pinning:\n  token: \"12345\"\nlimits:\n  download_timeout_secs: 60")
+        );
+        assert!(apply_override(&mut raw, "LIMITS__DOWNLOAD_TIMEOUT_SECS", "[").is_err());
+    }
+
+    #[test]
+    fn new_values_are_guessed() {
+        let mut raw = yaml("{}");
+        apply_override(&mut raw, "LIMITS__MAX_FILE_SIZE_BYTES", "1024").unwrap();
+        apply_override(&mut raw, "POLICIES__AUTO_JOIN", "false").unwrap();
+        apply_override(&mut raw, "POLICIES__ALLOWED_ROOMS", "[\"!a:example.com\"]").unwrap();
+        apply_override(&mut raw, "CLUSTER__NAME_TEMPLATE", "{room}/{filename}").unwrap();
+        apply_override(&mut raw, "REMOTE_PINNING__TOKEN", "\"12345\"").unwrap();
+        assert_eq!(
+            raw,
+            yaml(
+                "limits:\n  max_file_size_bytes: 1024\n\
+                 policies:\n  auto_join: false\n  allowed_rooms: [\"!a:example.com\"]\n\
+                 cluster:\n  name_template: \"{room}/{filename}\"\n\
+                 remote_pinning:\n  token: \"12345\""
+            )
+        );
+    }
+
+    #[test]
+    fn cli_variables_are_no_overrides() {
+        let vars = vec![
+            (
+                "IPFS_BOT_STORE_PASSPHRASE".to_string(),
+                "secret".to_string(),
+            ),
+            ("IPFS_BOT_PASSWORD".to_string(), "secret".to_string()),
+            (
+                "IPFS_BOT_LIMITS__DOWNLOAD_TIMEOUT_SECS".to_string(),
+                "60".to_string(),
+            ),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let excluded = vec![
+            "IPFS_BOT_STORE_PASSPHRASE".to_string(),
+            "IPFS_BOT_PASSWORD".to_string(),
+        ];
+        assert_eq!(
+            env_overrides(vars.into_iter(), &excluded),
+            vec![(
+                "IPFS_BOT_LIMITS__DOWNLOAD_TIMEOUT_SECS".to_string(),
+                "60".to_string()
+            )]
+        );
     }
 }
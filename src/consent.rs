@@ -0,0 +1,204 @@
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+use matrix_sdk::{
+    api::r0::sync::sync_events,
+    identifiers::{EventId, RoomId, UserId},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsentConfig {
+    /// Ask the sender of the media before archiving it for somebody else.
+    pub enabled: bool,
+    /// How long the sender has to answer in seconds.
+    pub timeout_secs: u64,
+    /// Reaction on the question that allows archiving.
+    pub approve: String,
+    /// Reaction on the question that refuses archiving.
+    pub deny: String,
+}
+
+impl Default for ConsentConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_secs: 60 * 60,
+            approve: "👍".to_string(),
+            deny: "👎".to_string(),
+        }
+    }
+}
+
+struct PendingConsent {
+    /// The question the bot posted.
+    prompt: EventId,
+    room_id: RoomId,
+    /// The only user whose answer counts.
+    media_sender: UserId,
+    decision: oneshot::Sender<bool>,
+}
+
+/// Questions waiting for the media sender to answer.
+#[derive(Default)]
+pub struct ConsentRequests {
+    pending: Mutex<Vec<PendingConsent>>,
+}
+
+impl ConsentRequests {
+    /// Waits for an answer of `media_sender` to the question `prompt`.
+    pub fn register(
+        &self,
+        prompt: EventId,
+        room_id: RoomId,
+        media_sender: UserId,
+    ) -> oneshot::Receiver<bool> {
+        let (decision, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().push(PendingConsent {
+            prompt,
+            room_id,
+            media_sender,
+            decision,
+        });
+        receiver
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn remove(&self, prompt: &EventId) {
+        self.pending.lock().unwrap().retain(|p| p.prompt != *prompt);
+    }
+
+    /// Answers the question `prompt` if it waits for `user_id`.
+    /// Returns `false` if there was nothing to answer.
+    pub fn decide(
+        &self,
+        room_id: &RoomId,
+        prompt: Option<&EventId>,
+        user_id: &UserId,
+        approved: bool,
+    ) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let prompt = match prompt {
+            Some(prompt) => prompt,
+            None => return false,
+        };
+        let index = pending.iter().position(|p| {
+            p.room_id == *room_id && p.media_sender == *user_id && p.prompt == *prompt
+        });
+        match index {
+            Some(index) => pending.remove(index).decision.send(approved).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Looks for reactions to questions in a sync response.
+    pub fn handle_sync(&self, response: &sync_events::Response, config: &ConsentConfig) {
+        for (room_id, room) in &response.rooms.join {
+            for event in &room.timeline.events {
+                let raw: Value = match serde_json::from_str(event.json().get()) {
+                    Ok(raw) => raw,
+                    Err(_) => continue,
+                };
+                if let Some((prompt, sender, key)) = reaction(&raw) {
+                    // Clients differ in sending emoji with or without variation selector.
+                    let key = key.trim_end_matches('\u{fe0f}');
+                    if key == config.approve.trim_end_matches('\u{fe0f}') {
+                        self.decide(room_id, Some(&prompt), &sender, true);
+                    } else if key == config.deny.trim_end_matches('\u{fe0f}') {
+                        self.decide(room_id, Some(&prompt), &sender, false);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How long the sender has to answer, e.g. "1 hour" or "90 seconds".
+pub fn format_timeout(secs: u64) -> String {
+    let (count, unit) = if secs >= 60 * 60 && secs % (60 * 60) == 0 {
+        (secs / (60 * 60), "hour")
+    } else if secs >= 60 && secs % 60 == 0 {
+        (secs / 60, "minute")
+    } else {
+        (secs, "second")
+    };
+    if count == 1 {
+        format!("{} {}", count, unit)
+    } else {
+        format!("{} {}s", count, unit)
+    }
+}
+
+/// The target, sender and key of a raw `m.reaction` event.
+fn reaction(raw: &Value) -> Option<(EventId, UserId, String)> {
+    if raw.get("type")?.as_str()? != "m.reaction" {
+        return None;
+    }
+    let sender = UserId::try_from(raw.get("sender")?.as_str()?).ok()?;
+    let relates_to = raw.get("content")?.get("m.relates_to")?;
+    let target = EventId::try_from(relates_to.get("event_id")?.as_str()?).ok()?;
+    let key = relates_to.get("key")?.as_str()?.to_string();
+    Some((target, sender, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids() -> (RoomId, UserId, EventId) {
+        (
+            RoomId::try_from("!room:example.com").unwrap(),
+            UserId::try_from("@sender:example.com").unwrap(),
+            EventId::try_from("$prompt:example.com").unwrap(),
+        )
+    }
+
+    #[test]
+    fn the_media_sender_answers_the_prompt() {
+        let (room_id, sender, prompt) = ids();
+        let requests = ConsentRequests::default();
+        let mut decision = requests.register(prompt.clone(), room_id.clone(), sender.clone());
+
+        assert!(requests.decide(&room_id, Some(&prompt), &sender, true));
+        assert_eq!(decision.try_recv(), Ok(true));
+        assert_eq!(requests.pending_count(), 0);
+    }
+
+    #[test]
+    fn only_the_media_sender_answers() {
+        let (room_id, sender, prompt) = ids();
+        let other = UserId::try_from("@other:example.com").unwrap();
+        let requests = ConsentRequests::default();
+        let _decision = requests.register(prompt.clone(), room_id.clone(), sender);
+
+        assert!(!requests.decide(&room_id, Some(&prompt), &other, true));
+        assert_eq!(requests.pending_count(), 1);
+    }
+
+    #[test]
+    fn answers_elsewhere_dont_count() {
+        let (room_id, sender, prompt) = ids();
+        let other_event = EventId::try_from("$other:example.com").unwrap();
+        let requests = ConsentRequests::default();
+        let _decision = requests.register(prompt, room_id.clone(), sender.clone());
+
+        assert!(!requests.decide(&room_id, Some(&other_event), &sender, true));
+        assert!(!requests.decide(&room_id, None, &sender, true));
+        assert_eq!(requests.pending_count(), 1);
+    }
+
+    #[test]
+    fn formats_the_timeout() {
+        assert_eq!(format_timeout(30), "30 seconds");
+        assert_eq!(format_timeout(90), "90 seconds");
+        assert_eq!(format_timeout(60), "1 minute");
+        assert_eq!(format_timeout(5 * 60), "5 minutes");
+        assert_eq!(format_timeout(60 * 60), "1 hour");
+    }
+}
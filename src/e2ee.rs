@@ -0,0 +1,346 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use matrix_sdk::{
+    api::r0::sync::sync_events,
+    events::{collections::all::RoomEvent, room::message::MessageEvent},
+    identifiers::{EventId, RoomId},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::admin::Alerts;
+use crate::get_room_event;
+use crate::reload::SharedConfig;
+use crate::send_to_device;
+
+/// To-device events that may carry the key for an undecryptable event. Room keys arrive
+/// Olm encrypted, so encrypted to-device events count as well.
+const ROOM_KEY_EVENTS: &[&str] = &["m.room_key", "m.forwarded_room_key", "m.room.encrypted"];
+
+/// How incoming SAS verification requests are handled.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationPolicy {
+    /// Accept and confirm every request, the emoji are only posted to the admin room.
+    AutoAccept,
+    /// Post requests and emoji to the admin room and wait for `!ipfs admin verify`.
+    Admin,
+    Ignore,
+}
+
+// TODO bootstrap cross-signing and restore room keys from the server-side key backup with a
+// recovery key from the config. matrix-sdk 0.1 neither keeps cross-signing keys nor can import
+// backed up Megolm sessions into its store, so both wait for an SDK update.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct E2eeConfig {
+    pub verification: VerificationPolicy,
+    /// Ask other devices for the keys of encrypted events the bot can't decrypt.
+    pub request_missing_keys: bool,
+    /// How long a command waits for the key of its related event in seconds.
+    pub decrypt_retry_timeout_secs: u64,
+}
+
+impl Default for E2eeConfig {
+    fn default() -> Self {
+        Self {
+            verification: VerificationPolicy::Admin,
+            request_missing_keys: true,
+            decrypt_retry_timeout_secs: 10 * 60,
+        }
+    }
+}
+
+/// Answers SAS verification requests according to the configured policy.
+#[derive(Clone)]
+pub struct Verifications {
+    client: Client,
+    config: SharedConfig,
+    alerts: Alerts,
+}
+
+impl Verifications {
+    pub fn new(client: Client, config: SharedConfig, alerts: Alerts) -> Self {
+        Self {
+            client,
+            config,
+            alerts,
+        }
+    }
+
+    /// Looks for verification events in the to-device events of a sync response.
+    pub async fn handle_sync(&self, response: &sync_events::Response) {
+        let policy = self.config.get().e2ee.verification;
+        if policy == VerificationPolicy::Ignore {
+            return;
+        }
+
+        for event in &response.to_device.events {
+            let raw: Value = match serde_json::from_str(event.json().get()) {
+                Ok(raw) => raw,
+                Err(_) => continue,
+            };
+            let event_type = raw.get("type").and_then(Value::as_str).unwrap_or_default();
+            let flow_id = match raw
+                .get("content")
+                .and_then(|content| content.get("transaction_id"))
+                .and_then(Value::as_str)
+            {
+                Some(flow_id) => flow_id,
+                None => continue,
+            };
+            let sender = raw
+                .get("sender")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            self.handle_event(policy, event_type, flow_id, sender).await;
+        }
+    }
+
+    async fn handle_event(
+        &self,
+        policy: VerificationPolicy,
+        event_type: &str,
+        flow_id: &str,
+        sender: &str,
+    ) {
+        let sas = match self.client.get_verification(flow_id).await {
+            Some(sas) => sas,
+            None => return,
+        };
+
+        match event_type {
+            "m.key.verification.start" => {
+                if policy == VerificationPolicy::AutoAccept {
+                    if let Err(e) = sas.accept().await {
+                        warn!("Unable to accept verification {}: {:?}", flow_id, e);
+                    }
+                } else {
+                    self.alerts
+                        .send(format!(
+                            "{} wants to verify the device {}. Accept with !ipfs admin verify {} accept",
+                            sender,
+                            sas.other_device().device_id(),
+                            flow_id
+                        ))
+                        .await;
+                }
+            }
+            "m.key.verification.key" => {
+                let emoji: Vec<String> = sas
+                    .emoji()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(emoji, name)| format!("{} ({})", emoji, name))
+                    .collect();
+                if policy == VerificationPolicy::AutoAccept {
+                    self.alerts
+                        .send(format!(
+                            "Confirming the verification with {}: {}",
+                            sender,
+                            emoji.join(" ")
+                        ))
+                        .await;
+                    if let Err(e) = sas.confirm().await {
+                        warn!("Unable to confirm verification {}: {:?}", flow_id, e);
+                    }
+                } else {
+                    self.alerts
+                        .send(format!(
+                            "Verification with {} shows: {}\nConfirm with !ipfs admin verify {} confirm or cancel it",
+                            sender,
+                            emoji.join(" "),
+                            flow_id
+                        ))
+                        .await;
+                }
+            }
+            "m.key.verification.mac" if sas.is_done() => {
+                info!("Verified {} of {}", sas.other_device().device_id(), sender);
+                self.alerts
+                    .send(format!(
+                        "Verified the device {} of {}",
+                        sas.other_device().device_id(),
+                        sender
+                    ))
+                    .await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Asks our own devices and the devices of the sender for the Megolm session of a raw
+/// `m.room.encrypted` event. Returns `false` if the event isn't one or sending failed.
+pub async fn request_room_key(client: &Client, room_id: &RoomId, raw_event: &Value) -> bool {
+    let content = match raw_event.get("content") {
+        Some(content) => content,
+        None => return false,
+    };
+    let field = |name: &str| content.get(name).and_then(Value::as_str);
+    let (algorithm, sender_key, session_id) =
+        match (field("algorithm"), field("sender_key"), field("session_id")) {
+            (Some(algorithm), Some(sender_key), Some(session_id)) => {
+                (algorithm, sender_key, session_id)
+            }
+            _ => return false,
+        };
+    let sender = match raw_event.get("sender").and_then(Value::as_str) {
+        Some(sender) => sender,
+        None => return false,
+    };
+    let session = client.base_client.session().read().await.clone();
+    let session = match session {
+        Some(session) => session,
+        None => return false,
+    };
+
+    let request = json!({
+        "action": "request",
+        "body": {
+            "algorithm": algorithm,
+            "room_id": room_id.to_string(),
+            "sender_key": sender_key,
+            "session_id": session_id,
+        },
+        "request_id": Uuid::new_v4().to_string(),
+        "requesting_device_id": session.device_id,
+    });
+    let mut messages = json!({});
+    messages[session.user_id.to_string()] = json!({ "*": request.clone() });
+    messages[sender] = json!({ "*": request });
+
+    let resp = client
+        .send(send_to_device::Request {
+            event_type: "m.room_key_request".to_string(),
+            txn_id: Uuid::new_v4().to_string(),
+            messages,
+        })
+        .await;
+    match resp {
+        Ok(_) => {
+            info!(
+                "Requested the room key for session {} in {}",
+                session_id, room_id
+            );
+            true
+        }
+        Err(e) => {
+            warn!("Unable to request the room key for {}: {:?}", session_id, e);
+            false
+        }
+    }
+}
+
+/// An event fetched from the server, decrypted if possible.
+pub enum FetchedEvent {
+    Message(MessageEvent),
+    /// An `m.room.encrypted` event we have no key for, as raw JSON.
+    Undecryptable(Value),
+    Other,
+}
+
+/// Fetches an event that is not in the timeline the bot has seen and decrypts it.
+pub async fn fetch_event(
+    client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<FetchedEvent, matrix_sdk::Error> {
+    let mut resp = client
+        .send(get_room_event::Request {
+            room_id: room_id.clone(),
+            event_id: event_id.clone(),
+        })
+        .await?;
+
+    // Returns the decrypted event if we have the Megolm session for it.
+    let (decrypted, _updated) = client
+        .base_client
+        .receive_joined_timeline_event(room_id, &mut resp.event)
+        .await?;
+    let event = decrypted.unwrap_or(resp.event);
+
+    Ok(match event.deserialize() {
+        Ok(RoomEvent::RoomMessage(message)) => FetchedEvent::Message(message),
+        Ok(RoomEvent::RoomEncrypted(_)) => FetchedEvent::Undecryptable(
+            serde_json::from_str(event.json().get()).unwrap_or_default(),
+        ),
+        _ => FetchedEvent::Other,
+    })
+}
+
+/// Whether a sync response contains to-device events that may be room keys.
+pub fn has_room_keys(response: &sync_events::Response) -> bool {
+    response.to_device.events.iter().any(|event| {
+        serde_json::from_str::<Value>(event.json().get())
+            .ok()
+            .and_then(|raw| raw.get("type").and_then(Value::as_str).map(String::from))
+            .map_or(false, |event_type| {
+                ROOM_KEY_EVENTS.contains(&event_type.as_str())
+            })
+    })
+}
+
+/// A command whose related event couldn't be decrypted yet.
+pub struct PendingCommand {
+    pub room_id: RoomId,
+    /// The encrypted event the command refers to.
+    pub event_id: EventId,
+    pub command: MessageEvent,
+    expires: Instant,
+}
+
+impl PendingCommand {
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+}
+
+/// Commands waiting for the key of their related event.
+#[derive(Default)]
+pub struct Undecryptable {
+    pending: Mutex<Vec<PendingCommand>>,
+}
+
+impl Undecryptable {
+    pub fn add(
+        &self,
+        room_id: RoomId,
+        event_id: EventId,
+        command: MessageEvent,
+        timeout: Duration,
+    ) {
+        self.put_back(PendingCommand {
+            room_id,
+            event_id,
+            command,
+            expires: Instant::now() + timeout,
+        });
+    }
+
+    pub fn put_back(&self, pending: PendingCommand) {
+        self.pending.lock().unwrap().push(pending);
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn take_all(&self) -> Vec<PendingCommand> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// Removes the commands that waited too long and returns them.
+    pub fn take_expired(&self) -> Vec<PendingCommand> {
+        let mut pending = self.pending.lock().unwrap();
+        let (expired, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut *pending)
+            .into_iter()
+            .partition(PendingCommand::expired);
+        *pending = waiting;
+        expired
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Error;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// Encrypt files before adding them to IPFS. The key is only shared in the reply.
+    pub enabled: bool,
+}
+
+/// Encrypts the file at `path` with a new random key using AES-256-GCM.
+/// Writes `<path>.enc` as nonce followed by the ciphertext and returns it with the
+/// base64 encoded key. The whole file is encrypted in memory, so files bigger than
+/// `max_size` are refused before they are read.
+pub fn encrypt_file(path: &Path, max_size: u64) -> Result<(PathBuf, String), Error> {
+    if fs::metadata(path)?.len() > max_size {
+        return Err(Error::TooLarge { limit: max_size });
+    }
+    let mut key = [0u8; KEY_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut key);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let plaintext = fs::read(path)?;
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+    let ciphertext = cipher
+        .encrypt(GenericArray::from_slice(&nonce), plaintext.as_ref())
+        .map_err(|_| Error::Crypto("encrypting the file failed".to_string()))?;
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend_from_slice(&ciphertext);
+    let mut encrypted_path = path.as_os_str().to_owned();
+    encrypted_path.push(".enc");
+    let encrypted_path = PathBuf::from(encrypted_path);
+    if let Err(e) = fs::write(&encrypted_path, encrypted) {
+        let _ = fs::remove_file(&encrypted_path);
+        return Err(e.into());
+    }
+
+    Ok((
+        encrypted_path,
+        base64::encode_config(&key, base64::URL_SAFE_NO_PAD),
+    ))
+}
+
+/// The size of a file of `size` bytes after `encrypt_file`.
+pub fn encrypted_size(size: u64) -> u64 {
+    size + (NONCE_LEN + TAG_LEN) as u64
+}
+
+/// Decodes a key returned by `encrypt_file`.
+pub fn decode_key(key: &str) -> Result<Vec<u8>, Error> {
+    let key = base64::decode_config(key, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| Error::Crypto("the key is not valid base64".to_string()))?;
+    if key.len() != KEY_LEN {
+        return Err(Error::Crypto(format!(
+            "the key has to be {} bytes",
+            KEY_LEN
+        )));
+    }
+    Ok(key)
+}
+
+/// Reverses `encrypt_file` for data fetched from IPFS, with a key from `decode_key`.
+pub fn decrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < NONCE_LEN {
+        return Err(Error::Crypto("the file is too short".to_string()));
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+    cipher
+        .decrypt(GenericArray::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::Crypto("wrong key or damaged file".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("ipfs-bot-test-{}-{}", process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypted() {
+        let path = temp_file("round-trip", b"a cat picture");
+        let (encrypted_path, key) = encrypt_file(&path, 1024).unwrap();
+        let encrypted = fs::read(&encrypted_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&encrypted_path).unwrap();
+
+        assert_eq!(encrypted.len() as u64, encrypted_size(13));
+        let key = decode_key(&key).unwrap();
+        assert_eq!(decrypt(&encrypted, &key).unwrap(), b"a cat picture");
+    }
+
+    #[test]
+    fn refuses_a_wrong_key() {
+        let path = temp_file("wrong-key", b"a cat picture");
+        let (encrypted_path, _) = encrypt_file(&path, 1024).unwrap();
+        let encrypted = fs::read(&encrypted_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&encrypted_path).unwrap();
+
+        let wrong_key = [7u8; KEY_LEN];
+        assert!(matches!(
+            decrypt(&encrypted, &wrong_key),
+            Err(Error::Crypto(_))
+        ));
+        assert!(matches!(decode_key("too-short"), Err(Error::Crypto(_))));
+        assert!(matches!(decode_key("not base64!"), Err(Error::Crypto(_))));
+    }
+
+    #[test]
+    fn refuses_files_over_the_limit() {
+        let path = temp_file("too-large", b"a cat picture");
+        let result = encrypt_file(&path, 4);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::TooLarge { limit: 4 })));
+    }
+}
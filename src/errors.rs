@@ -0,0 +1,115 @@
+use std::fmt;
+
+use crate::retry::RetryableError;
+
+/// Everything that can go wrong while archiving a single media event.
+#[derive(Debug)]
+pub enum Error {
+    /// The media repository could not be reached or returned an error status.
+    Download(reqwest::Error),
+    /// Writing or removing the temporary file failed.
+    Io(std::io::Error),
+    /// The IPFS HTTP API could not be reached or returned an error status.
+    IpfsApi(reqwest::Error),
+    /// The IPFS node refused a command and explained why.
+    IpfsCommand(String),
+    /// The IPFS API answered `add` without any hash.
+    EmptyIpfsResponse,
+    /// The media is bigger than `limits.max_file_size_bytes`.
+    TooLarge { limit: u64 },
+    /// Encrypting or decrypting a file failed.
+    Crypto(String),
+    /// The remote pinning service failed or refused to pin.
+    PinningService(String),
+    /// The IPFS Cluster API failed or no peer could pin the file.
+    Cluster(String),
+    /// A step still failed after all retries were used up.
+    Exhausted {
+        step: &'static str,
+        attempts: u32,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Classifies the error so the retry policy can decide whether another attempt makes sense.
+    pub fn kind(&self) -> Option<RetryableError> {
+        match self {
+            Error::Download(e) | Error::IpfsApi(e) => http_kind(e),
+            Error::Io(_) => Some(RetryableError::Io),
+            Error::IpfsCommand(_) => None,
+            Error::EmptyIpfsResponse => None,
+            Error::TooLarge { .. } => None,
+            Error::Crypto(_) => None,
+            Error::PinningService(_) => None,
+            Error::Cluster(_) => None,
+            Error::Exhausted { .. } => None,
+        }
+    }
+
+    /// Whether the IPFS node could not be reached at all, so another node may be tried.
+    pub fn node_unreachable(&self) -> bool {
+        match self {
+            Error::IpfsApi(e) => e.is_connect() || e.is_timeout(),
+            Error::Exhausted { source, .. } => source.node_unreachable(),
+            _ => false,
+        }
+    }
+}
+
+fn http_kind(e: &reqwest::Error) -> Option<RetryableError> {
+    if e.is_timeout() {
+        Some(RetryableError::Timeout)
+    } else if let Some(status) = e.status() {
+        if status.as_u16() == 429 {
+            Some(RetryableError::TooManyRequests)
+        } else if status.is_server_error() {
+            Some(RetryableError::ServerError)
+        } else {
+            None
+        }
+    } else if e.is_builder() || e.is_redirect() || e.is_decode() {
+        None
+    } else {
+        Some(RetryableError::Connect)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Download(e) => write!(f, "download failed: {}", e),
+            Error::Io(e) => write!(f, "file error: {}", e),
+            Error::IpfsCommand(e) => write!(f, "IPFS error: {}", e),
+            Error::IpfsApi(e) => write!(f, "IPFS error: {}", e),
+            Error::EmptyIpfsResponse => write!(f, "IPFS returned no hash"),
+            Error::TooLarge { limit } => write!(f, "file is bigger than {} bytes", limit),
+            Error::Crypto(e) => write!(f, "{}", e),
+            Error::PinningService(e) => write!(f, "pinning service error: {}", e),
+            Error::Cluster(e) => write!(f, "IPFS Cluster error: {}", e),
+            Error::Exhausted {
+                step,
+                attempts,
+                source,
+            } => write!(
+                f,
+                "{} failed after {} attempt(s): {}",
+                step, attempts, source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Download(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
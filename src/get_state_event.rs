@@ -0,0 +1,36 @@
+// Same as get_room_event: the ruma_api version has to match the one matrix-sdk uses
+use matrix_sdk::identifiers::RoomId;
+use ruma_api::ruma_api;
+
+ruma_api! {
+    metadata {
+        description: "Get the content of a state event with arbitrary type",
+        method: GET,
+        name: "get_state_event",
+        path: "/_matrix/client/r0/rooms/:room_id/state/:event_type/:state_key",
+        rate_limited: false,
+        requires_authentication: true,
+    }
+
+    request {
+        /// The room to look up the state in.
+        #[ruma_api(path)]
+        pub room_id: RoomId,
+
+        /// The type of state to look up.
+        #[ruma_api(path)]
+        pub event_type: String,
+
+        /// The key of the state to look up.
+        #[ruma_api(path)]
+        pub state_key: String,
+    }
+
+    response {
+        /// The content of the state event.
+        #[ruma_api(body)]
+        pub content: serde_json::Value,
+    }
+
+    error: matrix_sdk_common::api::Error
+}
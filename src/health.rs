@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::StatusCode;
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::http::{self, Handler, Reply};
+use crate::ipfs::IpfsApi;
+use crate::jobs::now;
+use crate::reload::SharedConfig;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Address to serve `/healthz` and `/readyz` on, e.g. `0.0.0.0:8080`. Disabled if unset.
+    pub listen: Option<String>,
+    /// The bot counts as unhealthy if no sync finished for this many seconds.
+    pub max_sync_age_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            max_sync_age_secs: 120,
+        }
+    }
+}
+
+/// How long `/readyz` waits for IPFS and the homeserver.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When the sync loop last got a response.
+#[derive(Clone)]
+pub struct SyncProgress(Arc<AtomicU64>);
+
+impl Default for SyncProgress {
+    /// Counts the start as progress so the bot isn't unhealthy during the initial sync.
+    fn default() -> Self {
+        Self(Arc::new(AtomicU64::new(now())))
+    }
+}
+
+impl SyncProgress {
+    pub fn synced(&self) {
+        self.0.store(now(), Ordering::SeqCst);
+    }
+
+    fn age_secs(&self) -> u64 {
+        now().saturating_sub(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// Everything the checks need.
+#[derive(Clone)]
+pub struct Checks {
+    pub client: Client,
+    pub http_client: reqwest::Client,
+    pub ipfs_client: IpfsApi,
+    pub config: SharedConfig,
+    pub store: PathBuf,
+    pub sync: SyncProgress,
+}
+
+impl Checks {
+    /// The process is alive and the sync loop is making progress.
+    pub async fn health(&self) -> Reply {
+        let age = self.sync.age_secs();
+        let max_age = self.config.get().health.max_sync_age_secs;
+        let sync = json!({
+            "ok": age <= max_age,
+            "last_sync_secs_ago": age,
+        });
+        report(vec![("sync", sync)])
+    }
+
+    /// IPFS and the homeserver are reachable and the store is writable.
+    pub async fn ready(&self) -> Reply {
+        let ipfs = match tokio::time::timeout(PROBE_TIMEOUT, self.ipfs_client.version()).await {
+            Ok(Ok(version)) => json!({ "ok": true, "version": version }),
+            Ok(Err(e)) => json!({ "ok": false, "error": e.to_string() }),
+            Err(_) => json!({ "ok": false, "error": "timed out" }),
+        };
+
+        let url = format!(
+            "{}/_matrix/client/versions",
+            self.client.homeserver().as_str().trim_end_matches('/')
+        );
+        let request = self.http_client.get(&url).timeout(PROBE_TIMEOUT);
+        let homeserver = match request.send().await {
+            Ok(resp) if resp.status().is_success() => json!({ "ok": true }),
+            Ok(resp) => json!({ "ok": false, "error": format!("status {}", resp.status()) }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        };
+
+        let probe = self.store.join(".readyz");
+        let store = match fs::write(&probe, b"ok").and_then(|_| fs::remove_file(&probe)) {
+            Ok(()) => json!({ "ok": true }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        };
+
+        report(vec![
+            ("ipfs", ipfs),
+            ("homeserver", homeserver),
+            ("store", store),
+        ])
+    }
+
+    pub fn routes(&self) -> Vec<(&'static str, Handler)> {
+        let health = self.clone();
+        let ready = self.clone();
+        vec![
+            (
+                "/healthz",
+                http::handler(move || {
+                    let checks = health.clone();
+                    async move { checks.health().await }
+                }),
+            ),
+            (
+                "/readyz",
+                http::handler(move || {
+                    let checks = ready.clone();
+                    async move { checks.ready().await }
+                }),
+            ),
+        ]
+    }
+}
+
+/// Answers 200 if all checks passed and 503 otherwise, with the details as JSON.
+fn report(checks: Vec<(&str, Value)>) -> Reply {
+    let ok = checks.iter().all(|(_, check)| check["ok"] == true);
+    let details: serde_json::Map<_, _> = checks
+        .into_iter()
+        .map(|(name, check)| (name.to_string(), check))
+        .collect();
+    let body = json!({
+        "status": if ok { "ok" } else { "failing" },
+        "checks": details,
+    });
+    let code = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, "application/json", body.to_string())
+}
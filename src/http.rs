@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use tracing::{info, warn};
+
+/// A response as status, content type and body.
+pub type Reply = (StatusCode, &'static str, String);
+
+pub type Handler =
+    Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Reply> + Send>> + Send + Sync + 'static>;
+
+/// Wraps an async function as route handler.
+pub fn handler<F, Fut>(f: F) -> Handler
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Reply> + Send + 'static,
+{
+    Arc::new(move || Box::pin(f()) as Pin<Box<dyn Future<Output = Reply> + Send>>)
+}
+
+/// Serves GET requests for `routes` on `addr` in the background.
+pub fn spawn(addr: &str, routes: HashMap<&'static str, Handler>) -> Result<(), String> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|e| format!("invalid listen address '{}': {}", addr, e))?;
+    let routes = Arc::new(routes);
+
+    let make_service = make_service_fn(move |_| {
+        let routes = routes.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let routes = routes.clone();
+                async move { Ok::<_, Infallible>(respond(&routes, request).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| format!("unable to listen on {}: {}", addr, e))?
+        .serve(make_service);
+
+    info!("Listening on http://{}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("HTTP server on {} failed: {}", addr, e);
+        }
+    });
+    Ok(())
+}
+
+async fn respond(
+    routes: &HashMap<&'static str, Handler>,
+    request: Request<Body>,
+) -> Response<Body> {
+    let handler = match routes.get(request.uri().path()) {
+        Some(handler) if request.method() == Method::GET => handler,
+        Some(_) => return status(StatusCode::METHOD_NOT_ALLOWED),
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    let (code, content_type, body) = handler().await;
+    Response::builder()
+        .status(code)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
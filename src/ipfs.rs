@@ -0,0 +1,143 @@
+use serde::Deserialize;
+
+use crate::config::IpfsApiAuth;
+use crate::errors::Error;
+
+/// Client of the IPFS HTTP API of one node. Unlike the ipfs-api crate it sends the
+/// credentials of `ipfs_api_auth` with every request.
+#[derive(Clone)]
+pub struct IpfsApi {
+    http_client: reqwest::Client,
+    api: String,
+    auth: Option<IpfsApiAuth>,
+}
+
+/// The body of an error answer of the API.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ApiError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VersionResponse {
+    version: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FilesStatResponse {
+    hash: String,
+}
+
+impl IpfsApi {
+    pub fn new(http_client: reqwest::Client, api: &str, auth: Option<IpfsApiAuth>) -> Self {
+        Self {
+            http_client,
+            api: api.trim_end_matches('/').to_string(),
+            auth,
+        }
+    }
+
+    /// A request for the API command `command`, e.g. `pin/add`.
+    pub fn request(&self, command: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}/api/v0/{}", self.api, command);
+        let request = self.http_client.post(&url);
+        match &self.auth {
+            Some(IpfsApiAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(IpfsApiAuth::Bearer { token }) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Sends `request`. Errors the node explains become `Error::IpfsCommand`, everything
+    /// else keeps its HTTP status so it can be retried.
+    pub async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, Error> {
+        let resp = request.send().await.map_err(Error::IpfsApi)?;
+        let status_error = match resp.error_for_status_ref() {
+            Ok(_) => return Ok(resp),
+            Err(e) => e,
+        };
+        let body = resp.text().await.unwrap_or_default();
+        match serde_json::from_str::<ApiError>(&body) {
+            Ok(error) => Err(Error::IpfsCommand(error.message)),
+            Err(_) => Err(Error::IpfsApi(status_error)),
+        }
+    }
+
+    async fn command(
+        &self,
+        command: &str,
+        args: &[(&str, &str)],
+    ) -> Result<reqwest::Response, Error> {
+        self.send(self.request(command).query(args)).await
+    }
+
+    pub async fn version(&self) -> Result<String, Error> {
+        let resp: VersionResponse = self
+            .command("version", &[])
+            .await?
+            .json()
+            .await
+            .map_err(Error::IpfsApi)?;
+        Ok(resp.version)
+    }
+
+    pub async fn pin_add(&self, hash: &str) -> Result<(), Error> {
+        self.command("pin/add", &[("arg", hash), ("recursive", "true")])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn pin_rm(&self, hash: &str) -> Result<(), Error> {
+        self.command("pin/rm", &[("arg", hash), ("recursive", "true")])
+            .await?;
+        Ok(())
+    }
+
+    /// The content of `hash`. Stops reading as soon as it gets bigger than `max_size`.
+    pub async fn cat(&self, hash: &str, max_size: Option<u64>) -> Result<Vec<u8>, Error> {
+        let mut resp = self.command("cat", &[("arg", hash)]).await?;
+        let mut data = Vec::new();
+        while let Some(chunk) = resp.chunk().await.map_err(Error::IpfsApi)? {
+            if let Some(limit) = max_size {
+                if (data.len() + chunk.len()) as u64 > limit {
+                    return Err(Error::TooLarge { limit });
+                }
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    pub async fn files_mkdir(&self, path: &str) -> Result<(), Error> {
+        self.command("files/mkdir", &[("arg", path), ("parents", "true")])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn files_cp(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.command("files/cp", &[("arg", from), ("arg", to)])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn files_rm(&self, path: &str) -> Result<(), Error> {
+        self.command("files/rm", &[("arg", path)]).await?;
+        Ok(())
+    }
+
+    /// The CID of the file or directory at `path`.
+    pub async fn files_stat(&self, path: &str) -> Result<String, Error> {
+        let resp: FilesStatResponse = self
+            .command("files/stat", &[("arg", path)])
+            .await?
+            .json()
+            .await
+            .map_err(Error::IpfsApi)?;
+        Ok(resp.hash)
+    }
+}
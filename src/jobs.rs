@@ -0,0 +1,239 @@
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::cluster::ClusterPin;
+use crate::remote_pin::RemotePin;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    InProgress,
+    Done,
+    Failed,
+}
+
+/// A single archive request and its outcome.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub id: u64,
+    pub room_id: String,
+    /// The media event that got archived.
+    pub event_id: String,
+    /// The command that started the job, the media event itself for automatic archiving.
+    #[serde(default)]
+    pub command_event_id: Option<String>,
+    pub requester: String,
+    pub filename: String,
+    pub status: JobStatus,
+    pub hash: Option<String>,
+    pub error: Option<String>,
+    /// Seconds since the UNIX epoch.
+    pub started: u64,
+    pub finished: Option<u64>,
+    /// When the hash got unpinned again.
+    #[serde(default)]
+    pub unpinned: Option<u64>,
+    /// Who unpinned it, the bot itself for automatic actions.
+    #[serde(default)]
+    pub unpinned_by: Option<String>,
+    /// The pin request at the remote pinning service.
+    #[serde(default)]
+    pub remote_pin: Option<RemotePin>,
+    /// API URL of the IPFS node that added the file.
+    #[serde(default)]
+    pub added_on: Option<String>,
+    /// Replica nodes that pinned the file.
+    #[serde(default)]
+    pub replicas: Vec<String>,
+    /// Replica nodes that failed to pin the file and are tried again.
+    #[serde(default)]
+    pub pending_replicas: Vec<String>,
+    /// The pin status across the IPFS Cluster peers, if the file was pinned through a cluster.
+    #[serde(default)]
+    pub cluster_pin: Option<ClusterPin>,
+    /// Where the file was copied to in the MFS of the node that added it.
+    #[serde(default)]
+    pub mfs_path: Option<String>,
+}
+
+/// History of all archive jobs, persisted as JSON next to the session.
+pub struct JobHistory {
+    path: PathBuf,
+    records: Mutex<Vec<JobRecord>>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl JobHistory {
+    pub fn load(path: PathBuf) -> Self {
+        let records = if path.exists() {
+            let f = OpenOptions::new().read(true).open(&path).unwrap();
+            serde_json::from_reader(f).expect("job history should be proper JSON")
+        } else {
+            Vec::new()
+        };
+        Self {
+            path,
+            records: Mutex::new(records),
+        }
+    }
+
+    /// Records a new job as in progress and returns its id.
+    pub async fn start(
+        &self,
+        room_id: String,
+        event_id: String,
+        command_event_id: String,
+        requester: String,
+        filename: String,
+    ) -> u64 {
+        let mut records = self.records.lock().await;
+        let id = records.last().map(|r| r.id + 1).unwrap_or(0);
+        records.push(JobRecord {
+            id,
+            room_id,
+            event_id,
+            command_event_id: Some(command_event_id),
+            requester,
+            filename,
+            status: JobStatus::InProgress,
+            hash: None,
+            error: None,
+            started: now(),
+            finished: None,
+            unpinned: None,
+            unpinned_by: None,
+            remote_pin: None,
+            added_on: None,
+            replicas: Vec::new(),
+            pending_replicas: Vec::new(),
+            cluster_pin: None,
+            mfs_path: None,
+        });
+        self.persist(&records);
+        id
+    }
+
+    pub async fn finish(&self, id: u64, result: Result<&str, String>) {
+        let mut records = self.records.lock().await;
+        if let Some(record) = records.iter_mut().find(|r| r.id == id) {
+            match result {
+                Ok(hash) => {
+                    record.status = JobStatus::Done;
+                    record.hash = Some(hash.to_string());
+                }
+                Err(error) => {
+                    record.status = JobStatus::Failed;
+                    record.error = Some(error);
+                }
+            }
+            record.finished = Some(now());
+        }
+        self.persist(&records);
+    }
+
+    /// Marks jobs that are still in progress as failed, e.g. because the bot stops.
+    pub async fn interrupt_unfinished(&self, reason: &str) -> usize {
+        let mut records = self.records.lock().await;
+        let mut interrupted = 0;
+        for record in records.iter_mut() {
+            if record.status == JobStatus::InProgress || record.status == JobStatus::Queued {
+                record.status = JobStatus::Failed;
+                record.error = Some(reason.to_string());
+                record.finished = Some(now());
+                interrupted += 1;
+            }
+        }
+        self.persist(&records);
+        interrupted
+    }
+
+    /// Whether a job was started for the command already.
+    pub async fn handled(&self, command_event_id: &str) -> bool {
+        self.records
+            .lock()
+            .await
+            .iter()
+            .any(|r| r.command_event_id.as_deref() == Some(command_event_id))
+    }
+
+    pub async fn records(&self) -> Vec<JobRecord> {
+        self.records.lock().await.clone()
+    }
+
+    pub async fn set_remote_pin(&self, id: u64, remote_pin: RemotePin) {
+        let mut records = self.records.lock().await;
+        if let Some(record) = records.iter_mut().find(|r| r.id == id) {
+            record.remote_pin = Some(remote_pin);
+        }
+        self.persist(&records);
+    }
+
+    pub async fn set_added_on(&self, id: u64, node: String) {
+        let mut records = self.records.lock().await;
+        if let Some(record) = records.iter_mut().find(|r| r.id == id) {
+            record.added_on = Some(node);
+        }
+        self.persist(&records);
+    }
+
+    pub async fn set_replicas(&self, id: u64, replicas: Vec<String>, pending: Vec<String>) {
+        let mut records = self.records.lock().await;
+        if let Some(record) = records.iter_mut().find(|r| r.id == id) {
+            record.replicas = replicas;
+            record.pending_replicas = pending;
+        }
+        self.persist(&records);
+    }
+
+    pub async fn set_cluster_pin(&self, id: u64, cluster_pin: ClusterPin) {
+        let mut records = self.records.lock().await;
+        if let Some(record) = records.iter_mut().find(|r| r.id == id) {
+            record.cluster_pin = Some(cluster_pin);
+        }
+        self.persist(&records);
+    }
+
+    pub async fn set_mfs_path(&self, id: u64, path: String) {
+        let mut records = self.records.lock().await;
+        if let Some(record) = records.iter_mut().find(|r| r.id == id) {
+            record.mfs_path = Some(path);
+        }
+        self.persist(&records);
+    }
+
+    pub async fn mark_unpinned(&self, id: u64, by: String) {
+        let mut records = self.records.lock().await;
+        if let Some(record) = records.iter_mut().find(|r| r.id == id) {
+            record.unpinned = Some(now());
+            record.unpinned_by = Some(by);
+        }
+        self.persist(&records);
+    }
+
+    fn persist(&self, records: &[JobRecord]) {
+        let tmp = self.path.with_extension("json.tmp");
+        let result = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(&f, records).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, &self.path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Unable to persist job history: {}", e);
+        }
+    }
+}
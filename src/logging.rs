@@ -0,0 +1,46 @@
+use std::env;
+
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Pretty,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Level or `RUST_LOG` style directives, e.g. `info,matrix_ipfs_bot=debug`.
+    /// `RUST_LOG` takes precedence if it is set.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Pretty,
+        }
+    }
+}
+
+/// Installs the global subscriber. Can only be called once.
+pub fn init(config: &LoggingConfig) {
+    let directives = env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| config.level.clone());
+    let filter = EnvFilter::try_new(&directives).unwrap_or_else(|e| {
+        eprintln!("Invalid log level '{}': {}", directives, e);
+        EnvFilter::new("info")
+    });
+    let builder = FmtSubscriber::builder().with_env_filter(filter);
+    let result = match config.format {
+        LogFormat::Pretty => tracing::subscriber::set_global_default(builder.finish()),
+        LogFormat::Json => tracing::subscriber::set_global_default(builder.json().finish()),
+    };
+    result.expect("setting default subscriber failed");
+}
@@ -1,113 +1,1369 @@
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::path::PathBuf;
-use std::{env, fs, process::exit};
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fs, process::exit};
 
-use bytes::buf::Buf;
-use ipfs_api::{IpfsClient, TryFromUri};
+use hyper::StatusCode;
 use matrix_sdk::{
     self,
-    events::collections::all::RoomEvent,
+    api::r0::{account::whoami, session::logout},
     events::room::{
-        ThumbnailInfo,
-        ImageInfo,
         member::MemberEventContent,
-        message::{MessageEvent, MessageEventContent, NoticeMessageEventContent, RelatesTo},
+        message::{MessageEvent, MessageEventContent, RelatesTo},
     },
     events::stripped::StrippedRoomMember,
-    identifiers::{RoomId, UserId},
+    identifiers::{EventId, RoomId, UserId},
     Client, ClientConfig, EventEmitter, Session as SDKSession, SyncRoom, SyncSettings,
 };
-use tracing::{info, warn, Level};
-use tracing_subscriber::FmtSubscriber;
+use structopt::StructOpt;
+use tracing::{debug, error, field, info, info_span, warn, Span};
+use tracing_futures::Instrument;
 use url::Url;
 
-use crate::config::Config;
-use crate::utils::{get_media_download_url, Session};
+use crate::add::AddOptions;
+use crate::admin::{Alerts, BanList};
+use crate::audit::{AuditAction, AuditEntry, AuditLog};
+use crate::cli::{Cli, Command, Credentials, LoginArgs};
+use crate::commands::{AdminCommand, BotCommand, ConfigCommand, VerifyAction};
+use crate::config::{Config, MediaType};
+use crate::consent::ConsentRequests;
+use crate::e2ee::{FetchedEvent, Undecryptable, Verifications};
+use crate::errors::Error;
+use crate::health::SyncProgress;
+use crate::ipfs::IpfsApi;
+use crate::jobs::{JobHistory, JobStatus};
+use crate::logging::LoggingConfig;
+use crate::nodes::{IpfsNode, IpfsNodes};
+use crate::permissions::Permission;
+use crate::progress::Progress;
+use crate::reactions::StatusReactions;
+use crate::reload::SharedConfig;
+use crate::remote_pin::{PinState, RemotePin, RemotePinMode};
+use crate::reply::{reply_relation, thread_relation, ReplyTarget};
+use crate::room_settings::{ReplyStyle, RoomSettings, RoomSettingsStore};
+use crate::shutdown::{JobGuard, Shutdown};
+use crate::sync::BacklogPolicy;
+use crate::utils::{get_media_download_url, media_source, temp_file_path, Media, Session};
 
+mod add;
+mod admin;
+mod audit;
+mod cli;
+mod cluster;
+mod commands;
 mod config;
+mod consent;
+mod e2ee;
+mod encrypt;
+mod errors;
 mod get_room_event;
+mod get_state_event;
+mod health;
+mod http;
+mod ipfs;
+mod jobs;
+mod logging;
+mod metrics;
+mod mfs;
+mod nodes;
+mod permissions;
+mod progress;
+mod reactions;
+mod reload;
+mod remote_pin;
+mod reply;
+mod retention;
+mod retry;
+mod room_settings;
+mod send_raw_event;
+mod send_state_event;
+mod send_to_device;
+mod shutdown;
+mod sync;
 mod utils;
 
+/// How many entries `!ipfs admin audit` shows at most.
+const AUDIT_QUERY_LIMIT: usize = 20;
+
+/// Shows up as the one that unpinned the result of an `--only-hash` dry run.
+const DRY_RUN_ACTOR: &str = "only-hash";
+
+/// Where a command came from and where answers to it should go.
+struct CommandContext {
+    room_id: RoomId,
+    /// The command, or the media itself when archiving automatically.
+    command_event_id: EventId,
+    sender: UserId,
+    reply: ReplyTarget,
+    reactions: StatusReactions,
+    /// The settings of the room the command was sent in.
+    settings: RoomSettings,
+    /// `ipfs add` options given with the command.
+    add_options: AddOptions,
+}
+
+/// A file `handle_media` added and pinned.
+struct Archived {
+    hash: String,
+    /// The key the file got encrypted with, if it was.
+    key: Option<String>,
+    /// API URL of the node the file was added on.
+    node: String,
+}
+
+#[derive(Clone)]
 struct CommandBot {
     /// This clone of the `Client` will send requests to the server,
     /// while the other keeps us in sync with the server using `sync_forever`.
     client: Client,
-    ipfs_client: IpfsClient,
-    config: Config,
+    /// Used to download media from the media repository.
+    http_client: reqwest::Client,
+    /// Client of the node `ipfs_api` points to.
+    ipfs_client: IpfsApi,
+    /// All IPFS nodes including the one of `ipfs_client`.
+    nodes: Arc<IpfsNodes>,
+    config: SharedConfig,
+    jobs: Arc<JobHistory>,
+    room_settings: Arc<RoomSettingsStore>,
+    /// Users the bot ignores.
+    bans: Arc<BanList>,
+    audit: Arc<AuditLog>,
+    alerts: Alerts,
+    /// Questions to media senders waiting for an answer.
+    consent: Arc<ConsentRequests>,
+    /// Commands waiting for the key of their related event.
+    undecryptable: Arc<Undecryptable>,
+    shutdown: Arc<Shutdown>,
 }
 
 impl CommandBot {
-    pub fn new(client: Client, config: Config) -> Self {
-        let ipfs_client = IpfsClient::from_str(&config.ipfs_api).unwrap();
+    pub fn new(
+        client: Client,
+        config: SharedConfig,
+        jobs: JobHistory,
+        bans: BanList,
+        audit: AuditLog,
+    ) -> Self {
+        let current = config.get();
+        let http_client = reqwest::Client::new();
+        let ipfs_client = IpfsApi::new(
+            http_client.clone(),
+            &current.ipfs_api,
+            current.ipfs_api_auth.clone(),
+        );
+        let nodes = IpfsNodes::new(&current, &http_client);
         Self {
+            room_settings: Arc::new(RoomSettingsStore::new(client.clone())),
+            alerts: Alerts::new(client.clone(), config.clone()),
             client,
+            http_client,
             ipfs_client,
+            nodes: Arc::new(nodes),
             config,
+            jobs: Arc::new(jobs),
+            bans: Arc::new(bans),
+            audit: Arc::new(audit),
+            consent: Arc::new(ConsentRequests::default()),
+            undecryptable: Arc::new(Undecryptable::default()),
+            shutdown: Arc::new(Shutdown::default()),
+        }
+    }
+
+    fn create_temp_file(&self, path: &Path) -> std::io::Result<File> {
+        debug!("Downloading to {}", path.display());
+        self.shutdown.track_temp_file(path);
+        File::create(path)
+    }
+
+    /// Removes a temporary file if it exists. Failures are only logged so they don't hide
+    /// the result of the job.
+    fn remove_temp_file(&self, path: &Path) {
+        self.shutdown.untrack_temp_file(path);
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Unable to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    async fn send_notice(
+        &self,
+        room_id: &RoomId,
+        body: String,
+        reply: &ReplyTarget,
+    ) -> Option<EventId> {
+        reply::send_notice(&self.client, room_id, body, reply).await
+    }
+
+    /// Fetches an event as raw JSON, e.g. to read relations the SDK doesn't know about.
+    async fn fetch_raw_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Option<serde_json::Value> {
+        let resp = self
+            .client
+            .send(get_room_event::Request {
+                room_id: room_id.clone(),
+                event_id: event_id.clone(),
+            })
+            .await;
+        match resp {
+            Ok(resp) => serde_json::from_str(resp.event.json().get()).ok(),
+            Err(e) => {
+                warn!("Unable to fetch event {}: {:?}", event_id, e);
+                None
+            }
+        }
+    }
+
+    /// The event a command replies to, from its decrypted `relates_to` or else the raw event.
+    async fn replied_to(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        relates_to: Option<&RelatesTo>,
+    ) -> Option<EventId> {
+        match relates_to {
+            Some(relates_to) => Some(relates_to.in_reply_to.event_id.clone()),
+            None => self
+                .fetch_raw_event(room_id, event_id)
+                .await
+                .and_then(|raw| reply_relation(&raw)),
         }
     }
 
-    fn get_temp_file(&self, filename: String) -> PathBuf {
-        let tmp_dir = env::temp_dir();
-        tmp_dir.join(filename)
+    fn gateway_link(&self, settings: &RoomSettings, filename: &str, hash: &str) -> String {
+        let config = self.config.get();
+        let gateway = settings
+            .preferred_gateway
+            .as_deref()
+            .unwrap_or_else(|| config.default_gateway());
+        format!("{}/ipfs/{}?filename={}", gateway, hash, filename)
+    }
+
+    /// Where to answer an event: as a reply, inside its thread if it was sent in one.
+    async fn reply_target(&self, room_id: &RoomId, event_id: &EventId) -> ReplyTarget {
+        ReplyTarget {
+            in_reply_to: Some(event_id.clone()),
+            thread_root: self
+                .fetch_raw_event(room_id, event_id)
+                .await
+                .and_then(|raw| thread_relation(&raw))
+                .map(|thread| thread.root),
+        }
     }
-    fn save_file(&self, filename: String, body: &[u8]) {
-        println!("file to download: '{}'", filename);
-        println!("will be located under: '{:?}'", &filename);
-        let filename = self.get_temp_file(filename);
-        let mut dest = { File::create(&filename).unwrap() };
 
-        dest.write_all(body).unwrap();
+    /// Builds the context for a job, applying the settings of the room.
+    async fn command_context(
+        &self,
+        room_id: &RoomId,
+        sender: &UserId,
+        command_event_id: &EventId,
+        reply: ReplyTarget,
+    ) -> CommandContext {
+        let config = self.config.get();
+        let settings = self.room_settings.get(room_id).await;
+        let reactions_enabled = settings.reply_style == Some(ReplyStyle::Reactions)
+            || config.reactions.enabled_in(room_id);
+
+        CommandContext {
+            room_id: room_id.clone(),
+            command_event_id: command_event_id.clone(),
+            sender: sender.clone(),
+            reply,
+            reactions: StatusReactions::new(
+                self.client.clone(),
+                room_id.clone(),
+                command_event_id.clone(),
+                &config.reactions,
+                reactions_enabled,
+            ),
+            settings,
+            add_options: AddOptions::default(),
+        }
+    }
+
+    /// Adds the file on the first node in `add_order` that is reachable.
+    async fn add_to_any_node(
+        &self,
+        filename: &Path,
+        options: &AddOptions,
+        pin: bool,
+    ) -> Result<(&IpfsNode, String), Error> {
+        let config = self.config.get();
+        let nodes = self.nodes.add_order();
+        let mut last_error = None;
+        for node in nodes {
+            let result = config
+                .retry
+                .run("add", || async move {
+                    add::add_file(&node.client, filename, options, pin)
+                        .await
+                        .map_err(|e| {
+                            metrics::IPFS_ERRORS.with_label_values(&["add"]).inc();
+                            e
+                        })
+                })
+                .await;
+            match result {
+                Ok(hash) => return Ok((node, hash)),
+                Err(e) if e.node_unreachable() => {
+                    warn!("{} is unreachable, trying the next node: {}", node.api, e);
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.expect("there is always at least one IPFS node"))
+    }
+
+    /// Downloads the media, adds it on the first reachable IPFS node and pins it there.
+    /// With `only_hash` set the CID is only computed.
+    async fn handle_media(
+        &self,
+        job: u64,
+        mxc_url: String,
+        raw_filename: &str,
+        progress: &Progress,
+        encrypt: bool,
+        options: &AddOptions,
+    ) -> Result<Archived, Error> {
+        let download_url = get_media_download_url(mxc_url);
+        let config = self.config.get();
+        let retry = &config.retry;
+
+        let max_size = config.limits.max_file_size_bytes;
+        let timeout = Duration::from_secs(config.limits.download_timeout_secs);
+        // Files are encrypted in memory, so encrypting needs a limit. `Config::validate` and
+        // the room settings make sure there is one unless the config changed since.
+        let encrypt_limit = if encrypt {
+            let missing =
+                || Error::Crypto("encryption requires limits.max_file_size_bytes".to_string());
+            Some(max_size.ok_or_else(missing)?)
+        } else {
+            None
+        };
+
+        let download_url = &download_url;
+        let plain_file = temp_file_path(job, raw_filename);
+        let plain_file_ref = &plain_file;
+        let timer = metrics::STEP_DURATION
+            .with_label_values(&["download"])
+            .start_timer();
+        let downloaded = retry
+            .run("download", || async move {
+                let mut response = self
+                    .http_client
+                    .get(download_url)
+                    .timeout(timeout)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let total = response.content_length();
+                if let (Some(total), Some(limit)) = (total, max_size) {
+                    if total > limit {
+                        return Err(Error::TooLarge { limit });
+                    }
+                }
+                let mut dest = self.create_temp_file(plain_file_ref)?;
+                let mut received = 0;
+                while let Some(chunk) = response.chunk().await? {
+                    received += chunk.len() as u64;
+                    if let Some(limit) = max_size {
+                        if received > limit {
+                            return Err(Error::TooLarge { limit });
+                        }
+                    }
+                    dest.write_all(&chunk)?;
+                    metrics::DOWNLOADED_BYTES.inc_by(chunk.len() as i64);
+                    progress.downloading(received, total).await;
+                }
+                Ok::<_, Error>(())
+            })
+            .await;
+        timer.observe_duration();
+        if let Err(e) = downloaded {
+            // Don't leave a partial download behind.
+            self.remove_temp_file(&plain_file);
+            return Err(e);
+        }
+
+        progress.adding().await;
+        let (filename, key) = if let Some(limit) = encrypt_limit {
+            let encrypted = encrypt::encrypt_file(&plain_file, limit);
+            self.remove_temp_file(&plain_file);
+            let (encrypted_file, key) = encrypted?;
+            self.shutdown.track_temp_file(&encrypted_file);
+            (encrypted_file, Some(key))
+        } else {
+            (plain_file, None)
+        };
+        let filename = &filename;
+        let size = match fs::metadata(filename) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                self.remove_temp_file(filename);
+                return Err(e.into());
+            }
+        };
+        let timer = metrics::STEP_DURATION
+            .with_label_values(&["add"])
+            .start_timer();
+        // With a cluster configured the file gets pinned through it instead.
+        let pin = config.cluster.api.is_none() && !options.is_dry_run();
+        let added = self.add_to_any_node(filename, options, pin).await;
+        timer.observe_duration();
+        self.remove_temp_file(filename);
+        let (node, hash) = added?;
+        if !options.is_dry_run() {
+            metrics::ADDED_BYTES.inc_by(size as i64);
+        }
+
+        // `add` already pinned it unless a cluster pins it or it was a dry run.
+        Ok(Archived {
+            hash,
+            key,
+            node: node.api.clone(),
+        })
     }
 
-    fn remove_file(&self, filename: String) {
-        let filename = self.get_temp_file(filename);
-        fs::remove_file(filename).unwrap();
+    /// Pins `hash` on the replica nodes. Returns a note for the reply if there are any.
+    async fn replicate(&self, job: u64, hash: &str, progress: &Progress) -> Option<String> {
+        let wanted = self.nodes.wanted_replicas(&self.config.get().replication);
+        if wanted == 0 {
+            return None;
+        }
+        progress.replicating().await;
+        let (replicas, pending) = self.nodes.replicate(hash, wanted, &[]).await;
+        let note = if pending.is_empty() {
+            format!("pinned on {} replica(s)", replicas.len())
+        } else {
+            format!(
+                "pinned on {} of {} replica(s), retrying the rest in the background",
+                replicas.len(),
+                wanted
+            )
+        };
+        self.jobs.set_replicas(job, replicas, pending).await;
+        Some(note)
     }
 
-    async fn send_link(
+    /// Pins `hash` through the IPFS Cluster, if there is one, and waits for its peers.
+    /// Returns a note about the peers for the reply.
+    async fn pin_on_cluster(
         &self,
+        job: u64,
+        hash: &str,
         room_id: &RoomId,
+        filename: &str,
+        progress: &Progress,
+    ) -> Result<Option<String>, Error> {
+        let config = self.config.get();
+        let cluster = &config.cluster;
+        if cluster.api.is_none() {
+            return Ok(None);
+        }
+
+        progress.pinning().await;
+        let timer = metrics::STEP_DURATION
+            .with_label_values(&["cluster_pin"])
+            .start_timer();
+        let name = cluster.pin_name(&room_id.to_string(), filename);
+        let pinned = cluster::pin(&self.http_client, cluster, hash, &name).await;
+        let peers = match pinned {
+            Ok(()) => cluster::status(&self.http_client, cluster, hash).await,
+            Err(e) => Err(e),
+        };
+        timer.observe_duration();
+
+        let pin = match peers {
+            Ok(pin) => pin,
+            Err(e) => {
+                metrics::IPFS_ERRORS.with_label_values(&["cluster"]).inc();
+                return Err(e);
+            }
+        };
+        if !pin.is_settled() {
+            // The peers can take minutes, so the job doesn't wait for them.
+            self.jobs.set_cluster_pin(job, pin).await;
+            let bot = self.clone();
+            let hash = hash.to_string();
+            tokio::spawn(async move {
+                let cluster = bot.config.get().cluster.clone();
+                match cluster::wait_for_peers(&bot.http_client, &cluster, &hash).await {
+                    Ok(pin) => {
+                        if pin.allocated > 0 && pin.errors.len() >= pin.allocated {
+                            metrics::IPFS_ERRORS.with_label_values(&["cluster"]).inc();
+                            bot.alerts
+                                .send(format!(
+                                    "No cluster peer could pin {} of job {}: {}",
+                                    hash,
+                                    job,
+                                    pin.errors.join(", ")
+                                ))
+                                .await;
+                        }
+                        bot.jobs.set_cluster_pin(job, pin).await;
+                    }
+                    Err(e) => warn!("Unable to check the cluster pin of {}: {}", hash, e),
+                }
+            });
+            return Ok(Some("pinning on the cluster in the background".to_string()));
+        }
+        let note = if pin.allocated > 0 && pin.errors.len() >= pin.allocated {
+            Err(Error::Cluster(format!(
+                "no peer could pin it: {}",
+                pin.errors.join(", ")
+            )))
+        } else if pin.pinned == pin.allocated {
+            Ok(Some(format!("pinned on {} cluster peer(s)", pin.pinned)))
+        } else {
+            Ok(Some(format!(
+                "pinned on {} of {} cluster peer(s)",
+                pin.pinned, pin.allocated
+            )))
+        };
+        self.jobs.set_cluster_pin(job, pin).await;
+        note
+    }
+
+    /// Pins `hash` at the remote pinning service, if there is one. Returns a note about the
+    /// remote pin for the reply, or an error if the job has to fail because of it.
+    async fn pin_remotely(
+        &self,
+        job: u64,
+        archived: &Archived,
+        filename: &str,
+        progress: &Progress,
+    ) -> Result<Option<String>, Error> {
+        let hash = &archived.hash;
+        let config = self.config.get();
+        let service = &config.remote_pinning;
+        if service.endpoint.is_none() {
+            return Ok(None);
+        }
+
+        progress.pinning_remotely().await;
+        let timer = metrics::STEP_DURATION
+            .with_label_values(&["remote_pin"])
+            .start_timer();
+        let result = remote_pin::pin(&self.http_client, service, hash, filename).await;
+        timer.observe_duration();
+        let remote = match result {
+            Ok(remote) => remote,
+            Err(e) => {
+                metrics::IPFS_ERRORS
+                    .with_label_values(&["remote_pin"])
+                    .inc();
+                return match service.mode {
+                    RemotePinMode::Only => Err(e),
+                    RemotePinMode::Additional => Ok(Some(format!("remote pinning failed: {}", e))),
+                };
+            }
+        };
+
+        if remote.is_pending() {
+            // Polling can take minutes, so the job doesn't wait for it.
+            self.jobs.set_remote_pin(job, remote.clone()).await;
+            let bot = self.clone();
+            let hash = hash.clone();
+            let node = archived.node.clone();
+            tokio::spawn(async move {
+                let service = bot.config.get().remote_pinning.clone();
+                let remote = remote_pin::wait(&bot.http_client, &service, remote).await;
+                match remote.status {
+                    PinState::Pinned => info!("The pinning service pinned {}", hash),
+                    PinState::Failed => {
+                        metrics::IPFS_ERRORS
+                            .with_label_values(&["remote_pin"])
+                            .inc();
+                        bot.alerts
+                            .send(format!(
+                                "The pinning service failed to pin {} of job {}",
+                                hash, job
+                            ))
+                            .await;
+                    }
+                    PinState::Queued | PinState::Pinning => {
+                        warn!("The pinning service is still pinning {}", hash)
+                    }
+                }
+                bot.record_remote_pin(job, &hash, &node, remote).await;
+            });
+            return Ok(Some(
+                "pinning on the pinning service in the background".to_string(),
+            ));
+        }
+
+        let failed = remote.status == PinState::Failed;
+        self.record_remote_pin(job, hash, &archived.node, remote)
+            .await;
+        match service.mode {
+            RemotePinMode::Only if failed => Err(Error::PinningService(
+                "the service failed to pin the content".to_string(),
+            )),
+            _ if failed => Ok(Some("the pinning service failed to pin it".to_string())),
+            _ => Ok(Some("pinned on the pinning service".to_string())),
+        }
+    }
+
+    /// Stores the remote pin with the job. Once the service pinned the content in `only`
+    /// mode the local pin on `node` is removed.
+    async fn record_remote_pin(&self, job: u64, hash: &str, node: &str, mut remote: RemotePin) {
+        let mode = self.config.get().remote_pinning.mode;
+        if remote.status == PinState::Pinned && mode == RemotePinMode::Only {
+            let node = self.nodes.get(node).unwrap_or_else(|| self.nodes.primary());
+            match node.client.pin_rm(hash).await {
+                Ok(_) => remote.replaced_local = true,
+                Err(e) => warn!("Unable to remove the local pin of {}: {}", hash, e),
+            }
+        }
+        self.jobs.set_remote_pin(job, remote).await;
+    }
+
+    /// Copies the archived file into the MFS of the node that added it, if enabled.
+    /// Returns a note for the reply if that failed, which doesn't fail the job.
+    async fn copy_to_mfs(
+        &self,
+        job: u64,
+        archived: &Archived,
+        room_id: &RoomId,
+        filename: &str,
+    ) -> Option<String> {
+        let config = self.config.get();
+        if !config.mfs.enabled {
+            return None;
+        }
+        let filename = match archived.key {
+            Some(_) => format!("{}.enc", filename),
+            None => filename.to_string(),
+        };
+        let path = config
+            .mfs
+            .path(&room_id.to_string(), &filename, chrono::Utc::now());
+        let node = self
+            .nodes
+            .get(&archived.node)
+            .unwrap_or_else(|| self.nodes.primary());
+        match mfs::copy(&node.client, &archived.hash, &path, job).await {
+            Ok(path) => {
+                self.jobs.set_mfs_path(job, path).await;
+                None
+            }
+            Err(e) => {
+                warn!("Unable to copy {} to {}: {}", archived.hash, path, e);
+                metrics::IPFS_ERRORS.with_label_values(&["mfs"]).inc();
+                Some(format!("not copied to MFS: {}", e))
+            }
+        }
+    }
+
+    /// Archives the media, records the job and reports the link or the failure to the room.
+    async fn archive(
+        &self,
+        ctx: &CommandContext,
+        media_event_id: &EventId,
+        media_type: MediaType,
+        mxc_url: String,
         filename: String,
+        running: JobGuard,
+    ) {
+        let span = info_span!(
+            "job",
+            id = field::Empty,
+            room = %ctx.room_id,
+            event = %media_event_id,
+            requester = %ctx.sender,
+            cid = field::Empty,
+        );
+        self.archive_job(ctx, media_event_id, media_type, mxc_url, filename, running)
+            .instrument(span)
+            .await
+    }
+
+    async fn archive_job(
+        &self,
+        ctx: &CommandContext,
+        media_event_id: &EventId,
+        media_type: MediaType,
+        mxc_url: String,
+        filename: String,
+        _running: JobGuard,
+    ) {
+        let config = self.config.get();
+        let media_label = format!("{:?}", media_type).to_lowercase();
+        let count = |outcome: &str| {
+            metrics::ARCHIVE_REQUESTS
+                .with_label_values(&[outcome, &media_label])
+                .inc()
+        };
+        let allowed_types = ctx
+            .settings
+            .allowed_types
+            .as_ref()
+            .unwrap_or(&config.policies.allowed_types);
+        if !allowed_types.contains(&media_type) {
+            count("not_allowed");
+            ctx.reactions.set(JobStatus::Failed).await;
+            self.send_notice(
+                &ctx.room_id,
+                format!("Archiving {:?} events is not allowed here!", media_type),
+                &ctx.reply,
+            )
+            .await;
+            return;
+        }
+
+        if self.shutdown.is_stopping() {
+            count("shutting_down");
+            ctx.reactions.set(JobStatus::Failed).await;
+            self.send_notice(
+                &ctx.room_id,
+                "The bot is shutting down, please try again later.".to_string(),
+                &ctx.reply,
+            )
+            .await;
+            return;
+        }
+
+        let job = self
+            .jobs
+            .start(
+                ctx.room_id.to_string(),
+                media_event_id.to_string(),
+                ctx.command_event_id.to_string(),
+                ctx.sender.to_string(),
+                filename.clone(),
+            )
+            .await;
+        Span::current().record("id", &job);
+        info!("Archiving '{}'", filename);
+        ctx.reactions.set(JobStatus::InProgress).await;
+
+        let mut progress_config = config.progress.clone();
+        if let Some(style) = ctx.settings.reply_style {
+            progress_config.enabled = style == ReplyStyle::Progress;
+        }
+        let progress = Progress::start(
+            self.client.clone(),
+            ctx.room_id.clone(),
+            &progress_config,
+            &filename,
+            &ctx.reply,
+        )
+        .await;
+
+        let encrypt = ctx.settings.encrypt.unwrap_or(config.encryption.enabled);
+        let options = ctx.add_options.or(&config.add);
+        let mut key = None;
+        let result = self
+            .handle_media(job, mxc_url, &filename, &progress, encrypt, &options)
+            .await;
+        let mut notes = Vec::new();
+        let result = match result {
+            Ok(archived) if options.is_dry_run() => {
+                notes.push("dry run, nothing was stored".to_string());
+                Ok((archived.hash, archived.key))
+            }
+            Ok(archived) => {
+                self.jobs.set_added_on(job, archived.node.clone()).await;
+                let cluster = self
+                    .pin_on_cluster(job, &archived.hash, &ctx.room_id, &filename, &progress)
+                    .await;
+                notes.extend(self.replicate(job, &archived.hash, &progress).await);
+                let remote = match cluster {
+                    Ok(note) => {
+                        notes.extend(note);
+                        self.pin_remotely(job, &archived, &filename, &progress)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match remote {
+                    Ok(note) => {
+                        notes.extend(note);
+                        notes.extend(
+                            self.copy_to_mfs(job, &archived, &ctx.room_id, &filename)
+                                .await,
+                        );
+                        Ok((archived.hash, archived.key))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        let mut entry = AuditEntry::new(AuditAction::Archive, ctx.sender.to_string());
+        entry.automatic = ctx.command_event_id == *media_event_id;
+        entry.room_id = Some(ctx.room_id.to_string());
+        entry.event_id = Some(media_event_id.to_string());
+        entry.command_event_id = Some(ctx.command_event_id.to_string());
+        match &result {
+            Ok((hash, _)) => {
+                entry.cid = Some(hash.clone());
+                if options.is_dry_run() {
+                    entry.detail = Some("only-hash, nothing was stored".to_string());
+                }
+            }
+            Err(e) => {
+                entry.action = AuditAction::ArchiveFailed;
+                entry.detail = Some(e.to_string());
+            }
+        }
+        self.audit.record(entry);
+
+        let body = match result {
+            Ok((hash, None)) => {
+                count("done");
+                Span::current().record("cid", &hash.as_str());
+                info!("Archived '{}'", filename);
+                self.jobs.finish(job, Ok(&hash)).await;
+                ctx.reactions.set(JobStatus::Done).await;
+                self.gateway_link(&ctx.settings, &filename, &hash)
+            }
+            Ok((hash, Some(encryption_key))) => {
+                count("done");
+                Span::current().record("cid", &hash.as_str());
+                info!("Archived '{}' encrypted", filename);
+                self.jobs.finish(job, Ok(&hash)).await;
+                ctx.reactions.set(JobStatus::Done).await;
+                let link = self.gateway_link(&ctx.settings, &format!("{}.enc", filename), &hash);
+                key = Some((hash, encryption_key));
+                format!("{} (encrypted, the key follows)", link)
+            }
+            Err(e) => {
+                count("failed");
+                self.alerts
+                    .send(format!(
+                        "Job {} archiving '{}' in {} failed: {}",
+                        job, filename, ctx.room_id, e
+                    ))
+                    .await;
+                self.jobs.finish(job, Err(e.to_string())).await;
+                ctx.reactions.set(JobStatus::Failed).await;
+                format!("Unable to upload {} to IPFS: {}", filename, e)
+            }
+        };
+
+        if options.is_dry_run() {
+            // Nothing got pinned, so retention, stats and repin-all have to skip the job.
+            self.jobs
+                .mark_unpinned(job, DRY_RUN_ACTOR.to_string())
+                .await;
+        }
+
+        let body = if notes.is_empty() {
+            body
+        } else {
+            format!("{} ({})", body, notes.join(", "))
+        };
+
+        if !progress.finish(body.clone()).await {
+            self.send_notice(&ctx.room_id, body, &ctx.reply).await;
+        }
+
+        // The key gets its own notice, so it never ends up in a progress edit and can be
+        // redacted on its own.
+        if let Some((hash, key)) = key {
+            self.send_notice(
+                &ctx.room_id,
+                format!(
+                    "Key for '{}': {}\nGet the file back with: !ipfs get {} {} {}",
+                    filename, key, hash, key, filename
+                ),
+                &ctx.reply,
+            )
+            .await;
+        }
+    }
+
+    /// Fetches an encrypted upload from IPFS, decrypts it and posts it to the room.
+    async fn get_command(
+        &self,
+        room_id: &RoomId,
+        event: &MessageEvent,
         hash: String,
-        related_event_original: Option<RelatesTo>,
+        key: String,
+        filename: Option<String>,
     ) {
-        let content = MessageEventContent::Notice(NoticeMessageEventContent {
-            body: format!(
-                "{}/ipfs/{}?filename={}",
-                self.config.ipfs_gateway, hash, filename
-            ),
-            format: None,
-            formatted_body: None,
-            relates_to: related_event_original,
+        let reply = self.reply_target(room_id, &event.event_id).await;
+        let filename = filename.unwrap_or_else(|| hash.clone());
+
+        // The media repository would get the decrypted file without attachment encryption.
+        let encrypted = match self.client.get_joined_room(room_id).await {
+            Some(room) => room.read().await.is_encrypted(),
+            None => false,
+        };
+        if encrypted {
+            self.send_notice(
+                room_id,
+                "!ipfs get is not available in encrypted rooms, the file would be uploaded \
+                 unencrypted."
+                    .to_string(),
+                &reply,
+            )
+            .await;
+            return;
+        }
+        // Nothing is fetched for a key that can't be right.
+        let key = match encrypt::decode_key(&key) {
+            Ok(key) => key,
+            Err(e) => {
+                self.send_notice(room_id, format!("Unable to get {}: {}", hash, e), &reply)
+                    .await;
+                return;
+            }
+        };
+
+        // The limit applies to the decrypted file, the encrypted one is a bit bigger.
+        let max_size = self
+            .config
+            .get()
+            .limits
+            .max_file_size_bytes
+            .map(encrypt::encrypted_size);
+        let result = match self.ipfs_client.cat(&hash, max_size).await {
+            Ok(data) => encrypt::decrypt(&data, &key),
+            Err(e) => Err(e),
+        };
+        let plaintext = match result {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                self.send_notice(room_id, format!("Unable to get {}: {}", hash, e), &reply)
+                    .await;
+                return;
+            }
+        };
+
+        let upload = self
+            .client
+            .upload(&mime::APPLICATION_OCTET_STREAM, &mut Cursor::new(plaintext))
+            .await;
+        match upload {
+            Ok(upload) => {
+                reply::send_file(&self.client, room_id, filename, upload.content_uri, &reply).await;
+            }
+            Err(e) => {
+                warn!("Unable to upload decrypted {}: {:?}", hash, e);
+                self.send_notice(
+                    room_id,
+                    format!("Unable to upload the decrypted file: {}", e),
+                    &reply,
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Answers `!ipfs room-root` with the CID of the MFS directory of the room.
+    async fn room_root_command(&self, room_id: &RoomId, event: &MessageEvent) {
+        let reply = self.reply_target(room_id, &event.event_id).await;
+        let config = self.config.get();
+        let body = if !config.mfs.enabled {
+            "Archived files aren't copied to MFS.".to_string()
+        } else {
+            let dir = config.mfs.room_dir(&room_id.to_string());
+            // The directory lives on the node that copied the latest file of the room.
+            let room = room_id.to_string();
+            let added_on = self
+                .jobs
+                .records()
+                .await
+                .into_iter()
+                .rev()
+                .find(|r| r.room_id == room && r.mfs_path.is_some() && r.unpinned.is_none())
+                .and_then(|r| r.added_on);
+            let node = added_on
+                .as_ref()
+                .and_then(|api| self.nodes.get(api))
+                .unwrap_or_else(|| self.nodes.primary());
+            match mfs::root(&node.client, &dir).await {
+                Ok(hash) => {
+                    let settings = self.room_settings.get(room_id).await;
+                    let gateway = settings
+                        .preferred_gateway
+                        .as_deref()
+                        .unwrap_or_else(|| config.default_gateway());
+                    format!("{} is {} ({}/ipfs/{})", dir, hash, gateway, hash)
+                }
+                Err(e) => format!("Unable to get the root of {}: {}", dir, e),
+            }
+        };
+        self.send_notice(room_id, body, &reply).await;
+    }
+
+    /// Archives the media right away, or once its sender approved if it belongs to
+    /// somebody else and the room asks for consent.
+    async fn archive_or_ask(
+        &self,
+        ctx: CommandContext,
+        media_event: &MessageEvent,
+        media: Media,
+        running: JobGuard,
+    ) {
+        let config = self.config.get();
+        let required = ctx
+            .settings
+            .require_consent
+            .unwrap_or(config.consent.enabled);
+        if !required || media_event.sender == ctx.sender {
+            self.archive(
+                &ctx,
+                &media_event.event_id,
+                media.media_type,
+                media.mxc_url,
+                media.filename,
+                running,
+            )
+            .await;
+            return;
+        }
+        // A shutdown doesn't wait for the answer, the job only runs once it was approved.
+        drop(running);
+
+        let timeout_secs = config.consent.timeout_secs;
+        let body = format!(
+            "{}: {} wants to archive your '{}' on IPFS, which publishes it permanently. \
+             React with {} to allow or {} to refuse, or reply with !ipfs approve or !ipfs deny \
+             within {}.",
+            media_event.sender,
+            ctx.sender,
+            media.filename,
+            config.consent.approve,
+            config.consent.deny,
+            consent::format_timeout(timeout_secs)
+        );
+        let prompt = match self.send_notice(&ctx.room_id, body, &ctx.reply).await {
+            Some(prompt) => prompt,
+            None => {
+                ctx.reactions.set(JobStatus::Failed).await;
+                return;
+            }
+        };
+        let decision = self.consent.register(
+            prompt.clone(),
+            ctx.room_id.clone(),
+            media_event.sender.clone(),
+        );
+
+        // Waiting here would block the sync loop that delivers the answer.
+        let bot = self.clone();
+        let media_event_id = media_event.event_id.clone();
+        let media_sender = media_event.sender.clone();
+        tokio::spawn(async move {
+            let timeout = Duration::from_secs(timeout_secs);
+            let approved = match tokio::time::timeout(timeout, decision).await {
+                Ok(Ok(approved)) => Some(approved),
+                _ => None,
+            };
+            bot.consent.remove(&prompt);
+
+            let body = match approved {
+                Some(true) => {
+                    let running = bot.shutdown.job();
+                    bot.archive(
+                        &ctx,
+                        &media_event_id,
+                        media.media_type,
+                        media.mxc_url,
+                        media.filename,
+                        running,
+                    )
+                    .await;
+                    return;
+                }
+                Some(false) => format!("{} refused archiving '{}'.", media_sender, media.filename),
+                None => format!(
+                    "{} didn't answer in time, '{}' was not archived.",
+                    media_sender, media.filename
+                ),
+            };
+            ctx.reactions.set(JobStatus::Failed).await;
+            bot.send_notice(&ctx.room_id, body, &ctx.reply).await;
         });
+    }
 
-        self.client
-            // send our message to the room we found the "!party" command in
-            // the last parameter is an optional Uuid which we don't care about.
-            .room_send(room_id, content, None)
-            .await
-            .unwrap();
+    /// Updates the gauges that are cheaper to read when scraped than to keep up to date.
+    async fn update_gauges(&self) {
+        let queued = self.consent.pending_count() + self.undecryptable.pending_count();
+        metrics::QUEUE_DEPTH.set(queued as i64);
+        metrics::ACTIVE_JOBS.set(self.shutdown.active_jobs() as i64);
+        let rooms = self.client.joined_rooms().read().await.len();
+        metrics::ROOMS_JOINED.set(rooms as i64);
     }
 
-    async fn handle_media(&self, mxc_url: String, raw_filename: String) -> String {
-        let download_url = get_media_download_url(mxc_url);
+    /// Runs the commands again whose related event can be decrypted now.
+    async fn retry_undecryptable(&self) {
+        self.expire_undecryptable().await;
+        for pending in self.undecryptable.take_all() {
+            match e2ee::fetch_event(&self.client, &pending.room_id, &pending.event_id).await {
+                Ok(FetchedEvent::Undecryptable(_)) => self.undecryptable.put_back(pending),
+                _ => {
+                    if let Some(room) = self.client.get_joined_room(&pending.room_id).await {
+                        self.on_room_message(SyncRoom::Joined(room), &pending.command)
+                            .await;
+                    }
+                }
+            }
+        }
+    }
 
-        let response = reqwest::get(&download_url).await.unwrap();
+    /// Gives up on commands whose key didn't arrive in time.
+    async fn expire_undecryptable(&self) {
+        for pending in self.undecryptable.take_expired() {
+            let reply = self
+                .reply_target(&pending.room_id, &pending.command.event_id)
+                .await;
+            self.send_notice(
+                &pending.room_id,
+                "I still can't decrypt that message, giving up.".to_string(),
+                &reply,
+            )
+            .await;
+        }
+    }
 
-        let content = response.bytes().await.unwrap();
+    /// Shows or changes the settings of a room. `can_edit` tells if the sender may
+    /// send the settings state event.
+    async fn config_command(
+        &self,
+        room_id: &RoomId,
+        event: &MessageEvent,
+        command: ConfigCommand,
+        can_edit: bool,
+    ) {
+        let mut settings = self.room_settings.get(room_id).await;
+        let body = match command {
+            ConfigCommand::Get(None) => {
+                let lines: Vec<String> = RoomSettings::KEYS
+                    .iter()
+                    .map(|key| format!("{}: {}", key, settings.get_key(key).unwrap()))
+                    .collect();
+                format!("Room settings:\n{}", lines.join("\n"))
+            }
+            ConfigCommand::Get(Some(key)) => match settings.get_key(&key) {
+                Ok(value) => format!("{}: {}", key, value),
+                Err(e) => e,
+            },
+            ConfigCommand::Set(_, _) if !can_edit => {
+                "You are not allowed to change the settings of this room.".to_string()
+            }
+            ConfigCommand::Set(key, value) => match settings.set_key(&key, &value) {
+                // Files are encrypted in memory, which needs a size limit.
+                Ok(())
+                    if key == "encrypt"
+                        && settings.encrypt == Some(true)
+                        && self.config.get().limits.max_file_size_bytes.is_none() =>
+                {
+                    "Encryption needs limits.max_file_size_bytes in the bot config.".to_string()
+                }
+                Ok(()) => match self.room_settings.set(room_id, &settings).await {
+                    Ok(()) => format!("{} is now {}", key, settings.get_key(&key).unwrap()),
+                    Err(e) => {
+                        warn!("Unable to store the settings of {}: {:?}", room_id, e);
+                        format!("Unable to store the settings: {}", e)
+                    }
+                },
+                Err(e) => e,
+            },
+        };
 
-        self.save_file(raw_filename.clone(), content.bytes());
+        let reply = self.reply_target(room_id, &event.event_id).await;
+        self.send_notice(room_id, body, &reply).await;
+    }
 
-        let filename = self.get_temp_file(raw_filename.clone());
-        let ipfs_resp = self.ipfs_client.add_path(&filename).await.unwrap();
-        self.remove_file(raw_filename);
+    /// Tells the sender of a command that they lack the permission for it.
+    async fn refuse(&self, room_id: &RoomId, event_id: &EventId, permission: Permission) {
+        let reply = self.reply_target(room_id, event_id).await;
+        self.send_notice(
+            room_id,
+            format!("Only {} may do that here.", permission),
+            &reply,
+        )
+        .await;
+    }
 
-        let hash = ipfs_resp.first().unwrap().hash.clone();
-        self.ipfs_client.pin_add(&hash, true).await.unwrap();
+    /// Runs a privileged command of a bot admin. `in_admin_room` tells if the command
+    /// was sent in the admin room.
+    async fn admin_command(
+        &self,
+        room_id: &RoomId,
+        event: &MessageEvent,
+        command: AdminCommand,
+        in_admin_room: bool,
+    ) {
+        let config = self.config.get();
+        let body = if !in_admin_room {
+            "Admin commands are only accepted in the admin room.".to_string()
+        } else {
+            match command {
+                AdminCommand::Stats => {
+                    let records = self.jobs.records().await;
+                    let count =
+                        |status: JobStatus| records.iter().filter(|r| r.status == status).count();
+                    let pinned: HashSet<&String> = records
+                        .iter()
+                        .filter(|r| r.status == JobStatus::Done && r.unpinned.is_none())
+                        .filter_map(|r| r.hash.as_ref())
+                        .collect();
+                    let rooms = self.client.joined_rooms().read().await.len();
+                    let repo = match admin::repo_stat(&self.nodes.primary().client).await {
+                        Ok(stat) => {
+                            format!("{} bytes in {} objects", stat.repo_size, stat.num_objects)
+                        }
+                        Err(e) => format!("unreachable ({})", e),
+                    };
+                    format!(
+                        "Jobs: {} done, {} failed, {} in progress\nPinned hashes: {}\nJoined rooms: {}\nBanned users: {}\nIPFS repository: {}",
+                        count(JobStatus::Done),
+                        count(JobStatus::Failed),
+                        count(JobStatus::InProgress),
+                        pinned.len(),
+                        rooms,
+                        self.bans.count(),
+                        repo
+                    )
+                }
+                AdminCommand::Rooms => {
+                    let rooms = self.client.joined_rooms();
+                    let rooms = rooms.read().await;
+                    let mut lines = Vec::new();
+                    for (room_id, room) in rooms.iter() {
+                        lines.push(format!(
+                            "{} ({})",
+                            room_id,
+                            room.read().await.display_name()
+                        ));
+                    }
+                    lines.sort();
+                    format!("Joined rooms:\n{}", lines.join("\n"))
+                }
+                AdminCommand::Leave(room) => match RoomId::try_from(room.as_str()) {
+                    Ok(room_id) if config.admin.is_admin_room(&room_id) => {
+                        "Refusing to leave the admin room.".to_string()
+                    }
+                    Ok(room_id) => match self.client.leave_room(&room_id).await {
+                        Ok(_) => format!("Left {}", room_id),
+                        Err(e) => format!("Unable to leave {}: {}", room_id, e),
+                    },
+                    Err(_) => format!("'{}' is not a room ID", room),
+                },
+                AdminCommand::BanUser(user) => match UserId::try_from(user.as_str()) {
+                    Ok(user_id) => {
+                        if self.bans.ban(&user_id) {
+                            format!("{} is now ignored", user_id)
+                        } else {
+                            format!("{} is banned already", user_id)
+                        }
+                    }
+                    Err(_) => format!("'{}' is not a user ID", user),
+                },
+                AdminCommand::Gc => match admin::repo_gc(&self.nodes.primary().client).await {
+                    Ok(removed) => {
+                        let mut entry = AuditEntry::new(AuditAction::Gc, event.sender.to_string());
+                        entry.detail = Some(format!("{} blocks removed", removed));
+                        self.audit.record(entry);
+                        format!("Garbage collection removed {} blocks", removed)
+                    }
+                    Err(e) => format!("Garbage collection failed: {}", e),
+                },
+                AdminCommand::RepinAll => {
+                    // Hashes pinned through the cluster are pinned there again, with their name.
+                    let hashes: HashMap<String, Option<String>> = self
+                        .jobs
+                        .records()
+                        .await
+                        .into_iter()
+                        .filter(|r| r.status == JobStatus::Done && r.unpinned.is_none())
+                        // Their content lives on the pinning service only.
+                        .filter(|r| r.remote_pin.as_ref().map_or(true, |p| !p.replaced_local))
+                        .filter_map(|r| {
+                            let name = r
+                                .cluster_pin
+                                .as_ref()
+                                .map(|_| config.cluster.pin_name(&r.room_id, &r.filename));
+                            r.hash.map(|hash| (hash, name))
+                        })
+                        .collect();
+                    let mut failed = Vec::new();
+                    for (hash, cluster_name) in &hashes {
+                        let result = match cluster_name {
+                            Some(name) if config.cluster.api.is_some() => {
+                                cluster::pin(&self.http_client, &config.cluster, hash, name).await
+                            }
+                            _ => {
+                                config
+                                    .retry
+                                    .run("pin_add", || async move {
+                                        self.ipfs_client.pin_add(hash).await
+                                    })
+                                    .await
+                            }
+                        };
+                        if let Err(e) = result {
+                            warn!("Unable to repin {}: {}", hash, e);
+                            failed.push(hash.clone());
+                        }
+                    }
+                    let mut entry =
+                        AuditEntry::new(AuditAction::RepinAll, event.sender.to_string());
+                    entry.detail = Some(format!(
+                        "{} of {} hashes repinned",
+                        hashes.len() - failed.len(),
+                        hashes.len()
+                    ));
+                    self.audit.record(entry);
+                    if failed.is_empty() {
+                        format!("Repinned {} hashes", hashes.len())
+                    } else {
+                        format!(
+                            "Repinned {} of {} hashes, failed: {}",
+                            hashes.len() - failed.len(),
+                            hashes.len(),
+                            failed.join(", ")
+                        )
+                    }
+                }
+                AdminCommand::Verify { flow_id, action } => {
+                    match self.client.get_verification(&flow_id).await {
+                        Some(sas) => {
+                            let result = match action {
+                                VerifyAction::Accept => sas.accept().await,
+                                VerifyAction::Confirm => sas.confirm().await,
+                                VerifyAction::Cancel => sas.cancel().await,
+                            };
+                            match result {
+                                Ok(()) => format!("Verification {}: {:?} sent", flow_id, action),
+                                Err(e) => format!("Verification {} failed: {}", flow_id, e),
+                            }
+                        }
+                        None => format!("There is no verification {}", flow_id),
+                    }
+                }
+                AdminCommand::Audit(filter) => {
+                    let entries = self.audit.query(&filter, AUDIT_QUERY_LIMIT);
+                    if entries.is_empty() {
+                        "No matching audit log entries".to_string()
+                    } else {
+                        let lines: Vec<String> = entries.iter().map(audit::describe).collect();
+                        format!("Latest audit log entries:\n{}", lines.join("\n"))
+                    }
+                }
+                AdminCommand::Reload => match self.config.reload() {
+                    Ok(()) => "Reloaded the config".to_string(),
+                    Err(e) => format!("Keeping the previous config, reload failed: {}", e),
+                },
+            }
+        };
 
-        hash
+        let reply = self.reply_target(room_id, &event.event_id).await;
+        self.send_notice(room_id, body, &reply).await;
     }
 }
 
@@ -121,341 +1377,667 @@ impl EventEmitter for CommandBot {
     ) {
         if let SyncRoom::Invited(room) = room {
             let room_id = room.read().await.room_id.clone();
+            let config = self.config.get();
+            let policies = &config.policies;
+            let allowed =
+                policies.room_allowed(&room_id.to_string()) || config.admin.is_admin_room(&room_id);
+            if !policies.auto_join || !allowed {
+                info!("Ignoring invite to {}", room_id);
+                return;
+            }
             self.client.join_room_by_id(&room_id).await.unwrap();
         }
     }
     async fn on_room_message(&self, room: SyncRoom, event: &MessageEvent) {
+        if self.shutdown.is_stopping() {
+            return;
+        }
         if let SyncRoom::Joined(room) = room {
+            // we clone here to hold the lock for as little time as possible.
+            let room_id = room.read().await.room_id.clone();
+
+            let config = self.config.get();
+            let policies = &config.policies;
+            if !policies.room_allowed(&room_id.to_string()) && !config.admin.is_admin_room(&room_id)
+            {
+                return;
+            }
+            if self.bans.is_banned(&event.sender) {
+                return;
+            }
+
             if let MessageEventContent::Text(text_event) = event.clone().content {
-                let test = serde_json::from_str::<ImageInfo>(r#"{"mimetype":"image/jpeg", "w":4998, "h":3333,"size":6467842}"#);
-                println!("{:?}", test);
                 let msg_body = text_event.body.clone();
 
-                // TODO fix e2ee relates_to with something like https://github.com/matrix-org/matrix-rust-sdk/blob/master/matrix_sdk_base/src/client.rs#L93 inside of receive_joined_timeline_event
+                let command = match commands::parse(&msg_body) {
+                    Some(command) => command,
+                    None => return,
+                };
+                // The command itself isn't logged, `!ipfs get` carries a decryption key.
+                debug!("Command from {} in {}", event.sender, room_id);
+
+                if !policies.user_allowed(&event.sender.to_string()) {
+                    info!("Ignoring !ipfs from {} in {}", event.sender, room_id);
+                    return;
+                }
 
-                if msg_body.contains("!ipfs") && text_event.relates_to.is_some() {
-                    println!("new !ipfs message");
-                    let related_event_original = text_event.relates_to.clone();
+                let settings = self.room_settings.get(&room_id).await;
+                let permission = match command {
+                    BotCommand::Archive(_) => settings
+                        .archive_permission
+                        .unwrap_or(policies.archive_permission),
+                    ref command => command.required_permission(),
+                };
+                // The sender of the media is only known once the related event got fetched.
+                if permission != Permission::MediaSender {
+                    let allowed =
+                        permission.allows(&*room.read().await, &event.sender, None, &config);
+                    if !allowed {
+                        self.refuse(&room_id, &event.event_id, permission).await;
+                        return;
+                    }
+                }
 
-                    // we clone here to hold the lock for as little time as possible.
-                    let room_id = room.read().await.room_id.clone();
-                    let mut related_events: Vec<MessageEvent> = room
-                        .read()
-                        .await
-                        .messages
-                        .iter()
-                        .filter(|x| {
-                            (**x).event_id
-                                == related_event_original
-                                    .as_ref()
-                                    .unwrap()
-                                    .in_reply_to
-                                    .event_id
-                        })
-                        .map(|x| (**x).clone())
-                        .collect();
-                    if related_events.is_empty() {
-                        // Fetch missing event
-                        let resp = self
-                            .client
-                            .send(get_room_event::Request {
-                                room_id: room_id.clone(),
-                                event_id: related_event_original
-                                    .clone()
-                                    .unwrap()
-                                    .in_reply_to
-                                    .event_id,
-                            })
+                let mut add_options = AddOptions::default();
+                let related_event_original = match command {
+                    BotCommand::Config(config_command) => {
+                        let can_edit = {
+                            let room = room.read().await;
+                            permissions::can_send_state(
+                                &room,
+                                &event.sender,
+                                room_settings::EVENT_TYPE,
+                            )
+                        };
+                        self.config_command(&room_id, event, config_command, can_edit)
+                            .await;
+                        return;
+                    }
+                    BotCommand::Admin(admin_command) => {
+                        let in_admin_room = config.admin.is_admin_room(&room_id);
+                        self.admin_command(&room_id, event, admin_command, in_admin_room)
                             .await;
+                        return;
+                    }
+                    BotCommand::Get {
+                        hash,
+                        key,
+                        filename,
+                    } => {
+                        self.get_command(&room_id, event, hash, key, filename).await;
+                        return;
+                    }
+                    BotCommand::RoomRoot => {
+                        self.room_root_command(&room_id, event).await;
+                        return;
+                    }
+                    BotCommand::Consent(approved) => {
+                        let prompt = self
+                            .replied_to(&room_id, &event.event_id, text_event.relates_to.as_ref())
+                            .await;
+                        if !self
+                            .consent
+                            .decide(&room_id, prompt.as_ref(), &event.sender, approved)
+                        {
+                            let reply = self.reply_target(&room_id, &event.event_id).await;
+                            self.send_notice(
+                                &room_id,
+                                "Reply to the question about your media with !ipfs approve or !ipfs deny."
+                                    .to_string(),
+                                &reply,
+                            )
+                            .await;
+                        }
+                        return;
+                    }
+                    BotCommand::Usage(usage) => {
+                        let reply = self.reply_target(&room_id, &event.event_id).await;
+                        self.send_notice(&room_id, usage.to_string(), &reply).await;
+                        return;
+                    }
+                    BotCommand::Archive(options) => {
+                        let replied_to = self
+                            .replied_to(&room_id, &event.event_id, text_event.relates_to.as_ref())
+                            .await;
+                        match replied_to {
+                            Some(event_id) => {
+                                add_options = options;
+                                event_id
+                            }
+                            None => return,
+                        }
+                    }
+                };
+                // Commands seen before a restart are caught up without running them twice.
+                if self.jobs.handled(&event.event_id.to_string()).await {
+                    return;
+                }
 
-                        println!("event: {:?}", resp);
+                // Commands sent inside a thread get answered in that thread. If the reply
+                // relation is only the thread fallback the user meant the thread root.
+                let command_thread = self
+                    .fetch_raw_event(&room_id, &event.event_id)
+                    .await
+                    .and_then(|raw| thread_relation(&raw));
+                let related_event_id = match &command_thread {
+                    Some(thread) if thread.is_falling_back => thread.root.clone(),
+                    _ => related_event_original,
+                };
 
-                        match resp {
-                            Ok(mut resp) => {
-                                println!("{:?}", resp.event.deserialize());
-                                let (event, _updated) = self
-                                    .client
-                                    .base_client
-                                    .receive_joined_timeline_event(&room_id, &mut resp.event)
-                                    .await
-                                    .unwrap();
-                                match event {
-                                    Some(event) => {
-                                        if let Ok(RoomEvent::RoomMessage(msg_event)) =
-                                            event.deserialize()
-                                        {
-                                            related_events.push(msg_event);
-                                        }
-                                    }
-                                    None => {
-                                        if let Ok(RoomEvent::RoomMessage(msg_event)) =
-                                            resp.event.deserialize()
-                                        {
-                                            related_events.push(msg_event);
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                println!("error: {:?}", e);
+                let reply = ReplyTarget {
+                    in_reply_to: Some(related_event_id.clone()),
+                    thread_root: command_thread.map(|thread| thread.root),
+                };
+                let mut ctx = self
+                    .command_context(&room_id, &event.sender, &event.event_id, reply)
+                    .await;
+                ctx.add_options = add_options;
+                ctx.reactions.set(JobStatus::Queued).await;
+
+                let mut related_events: Vec<MessageEvent> = room
+                    .read()
+                    .await
+                    .messages
+                    .iter()
+                    .filter(|x| (**x).event_id == related_event_id)
+                    .map(|x| (**x).clone())
+                    .collect();
+                if related_events.is_empty() {
+                    // Fetch missing event
+                    match e2ee::fetch_event(&self.client, &room_id, &related_event_id).await {
+                        Ok(FetchedEvent::Message(msg_event)) => related_events.push(msg_event),
+                        Ok(FetchedEvent::Undecryptable(raw)) => {
+                            // We don't have the session, maybe another device does.
+                            if config.e2ee.request_missing_keys {
+                                e2ee::request_room_key(&self.client, &room_id, &raw).await;
                             }
+                            self.undecryptable.add(
+                                room_id.clone(),
+                                related_event_id.clone(),
+                                event.clone(),
+                                Duration::from_secs(config.e2ee.decrypt_retry_timeout_secs),
+                            );
+                            self.send_notice(
+                                &room_id,
+                                "I can't decrypt that message yet. I'll try again once I get the key for it."
+                                    .to_string(),
+                                &ctx.reply,
+                            )
+                            .await;
+                            return;
                         }
+                        Ok(FetchedEvent::Other) => {}
+                        Err(e) => warn!("Unable to fetch event {}: {:?}", related_event_id, e),
                     }
-                    if !related_events.is_empty() {
-                        let related_event = related_events.first();
-
-                        if let Some(related_event) = related_event {
-                            // TODO handle media content
-                            info!("got related_event");
-
-                            match related_event.clone().content {
-                                MessageEventContent::Image(image_event) => {
-                                    info!("handling image event");
-
-                                    // Saving image
-                                    let filename = image_event.body.clone();
-                                    let hash = match image_event.url {
-                                        None => {
-                                            self.handle_media(
-                                                image_event.file.unwrap().url,
-                                                filename.clone(),
-                                            )
-                                            .await
-                                        }
-                                        Some(url) => self.handle_media(url, filename.clone()).await,
-                                    };
-
-                                    // Sending link
-                                    self.send_link(
-                                        &room_id,
-                                        filename.clone(),
-                                        hash,
-                                        related_event_original.clone(),
-                                    )
-                                    .await;
-
-                                    info!("image event message sent");
-                                }
-                                MessageEventContent::Video(video_event) => {
-                                    info!("handling video event");
-
-                                    // Saving video
-                                    let filename = video_event.body.clone();
-                                    let hash = match video_event.url {
-                                        None => {
-                                            self.handle_media(
-                                                video_event.file.unwrap().url,
-                                                filename.clone(),
-                                            )
-                                            .await
-                                        }
-                                        Some(url) => self.handle_media(url, filename.clone()).await,
-                                    };
-
-                                    // Sending link
-                                    self.send_link(
-                                        &room_id,
-                                        filename.clone(),
-                                        hash,
-                                        related_event_original.clone(),
-                                    )
-                                    .await;
-
-                                    info!("video event message sent");
-                                }
-                                MessageEventContent::File(file_event) => {
-                                    info!("handling file event");
-
-                                    // Saving file
-                                    let filename = file_event.body.clone();
-                                    let hash = match file_event.url {
-                                        None => {
-                                            self.handle_media(
-                                                file_event.file.unwrap().url,
-                                                filename.clone(),
-                                            )
-                                            .await
-                                        }
-                                        Some(url) => self.handle_media(url, filename.clone()).await,
-                                    };
-
-                                    // Sending link
-                                    self.send_link(
-                                        &room_id,
-                                        filename.clone(),
-                                        hash,
-                                        related_event_original.clone(),
-                                    )
-                                    .await;
-
-                                    info!("file event message sent");
-                                }
-                                MessageEventContent::Audio(audio_event) => {
-                                    info!("handling audio event");
-
-                                    // Saving audio
-                                    let filename = audio_event.body.clone();
-                                    let hash = match audio_event.url {
-                                        None => {
-                                            self.handle_media(
-                                                audio_event.file.unwrap().url,
-                                                filename.clone(),
-                                            )
-                                            .await
-                                        }
-                                        Some(url) => self.handle_media(url, filename.clone()).await,
-                                    };
-
-                                    // Sending link
-                                    self.send_link(
-                                        &room_id,
-                                        filename.clone(),
-                                        hash,
-                                        related_event_original.clone(),
-                                    )
-                                    .await;
-
-                                    info!("audio event message sent");
-                                }
-                                _ => {
-                                    info!("sending fallback response");
-
-                                    let content = MessageEventContent::Notice(NoticeMessageEventContent {
-                                        body: "Only Image, Video, File and Audio events are supported!".to_string(),
-                                        format: None,
-                                        formatted_body: None,
-                                        relates_to: related_event_original.clone(),
-                                    });
-
-                                    self.client
-                                        // send our message to the room we found the "!party" command in
-                                        // the last parameter is an optional Uuid which we don't care about.
-                                        .room_send(&room_id, content, None)
-                                        .await
-                                        .unwrap();
-
-                                    info!("fallback response message sent");
-                                }
+                }
+                if !related_events.is_empty() {
+                    let related_event = related_events.first();
+
+                    if let Some(related_event) = related_event {
+                        info!("got related_event");
+
+                        if permission == Permission::MediaSender {
+                            let allowed = permission.allows(
+                                &*room.read().await,
+                                &event.sender,
+                                Some(&related_event.sender),
+                                &config,
+                            );
+                            if !allowed {
+                                ctx.reactions.set(JobStatus::Failed).await;
+                                self.refuse(&room_id, &event.event_id, permission).await;
+                                return;
                             }
                         }
-                    } else {
-                        let content = MessageEventContent::Notice(NoticeMessageEventContent {
-                            body: "Unable to find related event!".to_string(),
-                            format: None,
-                            formatted_body: None,
-                            relates_to: related_event_original.clone(),
-                        });
-
-                        self.client
-                            // send our message to the room we found the "!party" command in
-                            // the last parameter is an optional Uuid which we don't care about.
-                            .room_send(&room_id, content, None)
-                            .await
-                            .unwrap();
 
-                        warn!("Unable to find related_event");
+                        // Media posted inside a thread gets its link in that thread too.
+                        if ctx.reply.thread_root.is_none() {
+                            ctx.reply.thread_root = self
+                                .fetch_raw_event(&room_id, &related_event.event_id)
+                                .await
+                                .and_then(|raw| thread_relation(&raw))
+                                .map(|thread| thread.root);
+                        }
+
+                        match media_source(&related_event.content) {
+                            Some(media) => {
+                                info!("handling {:?} event", media.media_type);
+
+                                // Uploading and sending link. The job runs in its own task
+                                // so it doesn't hold up the sync loop.
+                                let bot = self.clone();
+                                let related_event = related_event.clone();
+                                let running = self.shutdown.job();
+                                tokio::spawn(async move {
+                                    bot.archive_or_ask(ctx, &related_event, media, running)
+                                        .await;
+                                });
+                            }
+                            None => {
+                                info!("sending fallback response");
+                                ctx.reactions.set(JobStatus::Failed).await;
+
+                                self.send_notice(
+                                    &room_id,
+                                    "Only Image, Video, File and Audio events are supported!"
+                                        .to_string(),
+                                    &ctx.reply,
+                                )
+                                .await;
+
+                                info!("fallback response message sent");
+                            }
+                        }
                     }
+                } else {
+                    ctx.reactions.set(JobStatus::Failed).await;
+                    self.send_notice(
+                        &room_id,
+                        "Unable to find related event!".to_string(),
+                        &ctx.reply,
+                    )
+                    .await;
+
+                    warn!("Unable to find related_event");
+                }
+            } else if let Some(media) = media_source(&event.content) {
+                // Rooms can opt into archiving all media without a command.
+                let settings = self.room_settings.get(&room_id).await;
+                if settings.auto_archive != Some(true)
+                    || !policies.user_allowed(&event.sender.to_string())
+                    || self.jobs.handled(&event.event_id.to_string()).await
+                {
+                    return;
                 }
+                // The sender posted the media themselves, so this only matters for stricter rules.
+                let permission = settings
+                    .archive_permission
+                    .unwrap_or(policies.archive_permission);
+                let allowed = permission.allows(
+                    &*room.read().await,
+                    &event.sender,
+                    Some(&event.sender),
+                    &config,
+                );
+                if !allowed {
+                    return;
+                }
+                info!("auto archiving {:?} event", media.media_type);
+
+                let reply = self.reply_target(&room_id, &event.event_id).await;
+                let ctx = self
+                    .command_context(&room_id, &event.sender, &event.event_id, reply)
+                    .await;
+                let bot = self.clone();
+                let event_id = event.event_id.clone();
+                let running = self.shutdown.job();
+                tokio::spawn(async move {
+                    bot.archive(
+                        &ctx,
+                        &event_id,
+                        media.media_type,
+                        media.mxc_url,
+                        media.filename,
+                        running,
+                    )
+                    .await;
+                });
             }
         }
     }
 }
 
-async fn login_and_sync(
-    homeserver_url: String,
-    username: String,
-    password: String,
-) -> Result<(), matrix_sdk::Error> {
-    // the location for `JsonStore` to save files to
-    let mut home = dirs::home_dir().expect("no home directory found");
-    home.push("ipfs_bot");
-    fs::create_dir_all(&home).unwrap();
-
-    let client_config = ClientConfig::new()
-        .store_path(&home)
-        .passphrase(password.clone());
-
-    let homeserver_url = Url::parse(&homeserver_url).expect("Couldn't parse the homeserver URL");
+fn fail(message: &str) -> ! {
+    error!("{}", message);
+    exit(1)
+}
+
+/// The location for `JsonStore` to save files to.
+fn store_dir(cli: &Cli, config: &Config) -> PathBuf {
+    let store = match cli.store.as_ref().or_else(|| config.store_path.as_ref()) {
+        Some(store) => store.clone(),
+        None => {
+            let mut home = dirs::home_dir().expect("no home directory found");
+            home.push("ipfs_bot");
+            home
+        }
+    };
+    fs::create_dir_all(&store).unwrap();
+    store
+}
+
+fn create_client(cli: &Cli, store: &Path, homeserver_url: &str) -> Client {
+    let mut client_config = ClientConfig::new().store_path(store);
+    match cli.store_passphrase() {
+        Ok(Some(passphrase)) => client_config = client_config.passphrase(passphrase),
+        Ok(None) => warn!("No store passphrase given, the encryption store is not protected"),
+        Err(e) => fail(&format!("Unable to read the store passphrase: {}", e)),
+    }
+
+    let homeserver_url = Url::parse(homeserver_url).expect("Couldn't parse the homeserver URL");
     // create a new Client with the given homeserver url and config
-    let mut client = Client::new_with_config(homeserver_url, client_config).unwrap();
-
-    let mut session = home.clone();
-    session.push("session.json");
-    if session.exists() {
-        let f = OpenOptions::new().read(true).open(&session).unwrap();
-        let json: Session = serde_json::from_reader(f).expect("file should be proper JSON");
-        let session = SDKSession {
-            access_token: json.access_token,
-            user_id: UserId::try_from(json.user_id).unwrap(),
-            device_id: json.device_id,
-        };
-        client.restore_login(session).await.unwrap();
-    } else {
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&session)
-            .unwrap();
-
-        let login_response = client
-            .login(
-                username.clone(),
-                password,
-                None,
-                Some("ipfs bot".to_string()),
-            )
-            .await?;
+    Client::new_with_config(homeserver_url, client_config).unwrap()
+}
 
-        let session = Session {
-            access_token: login_response.access_token,
-            user_id: login_response.user_id.to_string(),
-            device_id: login_response.device_id,
+/// Restores the session stored in `store`, if there is one.
+async fn restore_session(
+    cli: &Cli,
+    store: &Path,
+    homeserver: Option<String>,
+) -> Result<Option<Client>, matrix_sdk::Error> {
+    let session = match Session::load(&store.join("session.json")) {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    let homeserver = match session.homeserver.clone().or(homeserver) {
+        Some(homeserver) => homeserver,
+        None => fail("--homeserver is required for sessions stored by older versions"),
+    };
+    // Older versions protected the encryption store with the login password, opening it
+    // without a passphrase would fail later on.
+    if session.homeserver.is_none() && cli.store_passphrase() == Ok(None) {
+        fail(&format!(
+            "The store was created by an older version, set {} to the login password",
+            cli.store_passphrase_env
+        ));
+    }
+
+    let mut client = create_client(cli, store, &homeserver);
+    let session = SDKSession {
+        access_token: session.access_token,
+        user_id: UserId::try_from(session.user_id).unwrap(),
+        device_id: session.device_id,
+    };
+    client.restore_login(session).await?;
+    Ok(Some(client))
+}
+
+/// Restores the stored session or logs in with the given credentials and stores the new session.
+async fn login(
+    cli: &Cli,
+    config: &Config,
+    args: &LoginArgs,
+) -> Result<(Client, PathBuf), matrix_sdk::Error> {
+    let mut args = args.clone();
+    args.apply_config(config);
+
+    let store = store_dir(cli, config);
+    let session_path = store.join("session.json");
+
+    if let Some(client) = restore_session(cli, &store, args.homeserver.clone()).await? {
+        return Ok((client, store));
+    }
+
+    let homeserver = match &args.homeserver {
+        Some(homeserver) => homeserver.clone(),
+        None => fail("--homeserver is required to log in"),
+    };
+    let user = match &args.user {
+        Some(user) => user.clone(),
+        None => fail("--user is required to log in"),
+    };
+    let mut client = create_client(cli, &store, &homeserver);
+
+    let session = match args.credentials() {
+        Ok(Some(Credentials::Password(password))) => {
+            let login_response = client
+                .login(
+                    user,
+                    password,
+                    args.device_id.clone(),
+                    Some("ipfs bot".to_string()),
+                )
+                .await?;
+
+            Session {
+                access_token: login_response.access_token,
+                user_id: login_response.user_id.to_string(),
+                device_id: login_response.device_id,
+                homeserver: Some(homeserver),
+            }
+        }
+        Ok(Some(Credentials::AccessToken(access_token))) => {
+            let user_id = match UserId::try_from(user.as_str()) {
+                Ok(user_id) => user_id,
+                Err(_) => fail("--user has to be a full user ID when using an access token"),
+            };
+            let device_id = match &args.device_id {
+                Some(device_id) => device_id.clone(),
+                None => fail("--device-id is required when using an access token"),
+            };
+            client
+                .restore_login(SDKSession {
+                    access_token: access_token.clone(),
+                    user_id: user_id.clone(),
+                    device_id: device_id.clone(),
+                })
+                .await?;
+
+            // make sure the token is valid and belongs to the given user
+            let whoami = client.send(whoami::Request {}).await?;
+            if whoami.user_id != user_id {
+                fail(&format!(
+                    "The access token belongs to {} and not to {}",
+                    whoami.user_id, user_id
+                ));
+            }
+
+            Session {
+                access_token,
+                user_id: user_id.to_string(),
+                device_id,
+                homeserver: Some(homeserver),
+            }
+        }
+        Ok(None) => fail("No stored session found, a password or access token is required"),
+        Err(e) => fail(&e),
+    };
+
+    if let Err(e) = session.save(&session_path) {
+        fail(&format!("Unable to store the session: {}", e));
+    }
+
+    Ok((client, store))
+}
+
+/// Runs the bot until SIGINT or SIGTERM and returns the exit code.
+async fn run(cli: &Cli, config: Config, args: &LoginArgs) -> Result<i32, matrix_sdk::Error> {
+    let (client, store) = login(cli, &config, args).await?;
+
+    if let Some(user_id) = client.user_id().await {
+        info!("Logged in as {}", user_id);
+    }
+
+    let jobs = JobHistory::load(store.join("jobs.json"));
+    let bans = BanList::load(store.join("banned_users.json"));
+    let audit = AuditLog::new(&config.audit, &store);
+    let config = SharedConfig::new(cli.config.clone(), cli.env_vars(), config);
+
+    let bot = CommandBot::new(client.clone(), config, jobs, bans, audit);
+    bot.config.spawn_watchers(bot.alerts.clone());
+    let consent = bot.consent.clone();
+    let shared_config = bot.config.clone();
+    let retry_bot = bot.clone();
+    let sync_progress = SyncProgress::default();
+
+    // Endpoints configured with the same address share one server.
+    let mut servers: HashMap<String, HashMap<&'static str, http::Handler>> = HashMap::new();
+    if let Some(listen) = &shared_config.get().metrics.listen {
+        let metrics_bot = bot.clone();
+        let handler = http::handler(move || {
+            let bot = metrics_bot.clone();
+            async move {
+                bot.update_gauges().await;
+                (
+                    StatusCode::OK,
+                    "text/plain; version=0.0.4",
+                    metrics::render(),
+                )
+            }
+        });
+        servers
+            .entry(listen.clone())
+            .or_default()
+            .insert("/metrics", handler);
+    }
+    if let Some(listen) = &shared_config.get().health.listen {
+        let checks = health::Checks {
+            client: client.clone(),
+            http_client: bot.http_client.clone(),
+            ipfs_client: bot.ipfs_client.clone(),
+            config: shared_config.clone(),
+            store: store.clone(),
+            sync: sync_progress.clone(),
         };
+        servers
+            .entry(listen.clone())
+            .or_default()
+            .extend(checks.routes());
+    }
+    for (listen, routes) in servers {
+        if let Err(e) = http::spawn(&listen, routes) {
+            fail(&format!("Unable to serve HTTP endpoints: {}", e));
+        }
+    }
+    let shutdown = bot.shutdown.clone();
+    let jobs = bot.jobs.clone();
+    let verifications = Verifications::new(client.clone(), bot.config.clone(), bot.alerts.clone());
+    admin::spawn_monitor(bot.nodes.clone(), bot.config.clone(), bot.alerts.clone());
+    nodes::spawn_replication(bot.nodes.clone(), bot.jobs.clone(), bot.config.clone());
+    retention::spawn(
+        bot.nodes.clone(),
+        bot.http_client.clone(),
+        bot.config.clone(),
+        bot.jobs.clone(),
+        bot.room_settings.clone(),
+        bot.audit.clone(),
+    );
 
-        serde_json::to_writer(&f, &session).unwrap();
+    let token_path = store.join("sync_token");
+    let token = sync::load_token(&token_path);
+    let catch_up = shared_config.get().sync.backlog == BacklogPolicy::CatchUp && token.is_some();
+    let mut bot = Some(Box::new(bot));
+    if catch_up {
+        // Handlers see the events of the initial sync, i.e. everything since the last run.
+        client.add_event_emitter(bot.take().unwrap()).await;
     }
 
-    println!("logged in as {}", username);
+    let mut initial = SyncSettings::default();
+    if let Some(token) = token {
+        initial = initial.token(token);
+    }
+    let response = client.sync(initial).await?;
+    sync::save_token(&token_path, &response.next_batch);
+    sync_progress.synced();
 
     // add our CommandBot to be notified of incoming messages, we do this after the initial
     // sync to avoid responding to messages before the bot was running.
-    client
-        .add_event_emitter(Box::new(CommandBot::new(client.clone(), Config::load())))
-        .await;
+    if let Some(bot) = bot {
+        client.add_event_emitter(bot).await;
+    }
 
     // since we called sync before we `sync_forever` we must pass that sync token to
     // `sync_forever`
-    let settings = SyncSettings::default();
-    // this keeps state from the server streaming in to CommandBot via the EventEmitter trait
-    client.sync_forever(settings, |_| async {}).await;
+    let settings = SyncSettings::default().token(response.next_batch);
+    // this keeps state from the server streaming in to CommandBot via the EventEmitter trait.
+    // Jobs run in their own tasks, so a long upload doesn't stall the sync loop.
+    let sync_client = client.clone();
+    let sync_config = shared_config.clone();
+    let sync_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        sync_client
+            .sync_forever(settings, |response| {
+                // Answers to consent questions come in as reactions the EventEmitter doesn't cover.
+                consent.handle_sync(&response, &sync_config.get().consent);
+                // Commands are dropped while draining, so a restart has to see them again.
+                if !sync_shutdown.is_stopping() {
+                    sync::save_token(&token_path, &response.next_batch);
+                }
+                sync_progress.synced();
+                let verifications = verifications.clone();
+                let retry_bot = retry_bot.clone();
+                async move {
+                    verifications.handle_sync(&response).await;
+                    if e2ee::has_room_keys(&response) {
+                        retry_bot.retry_undecryptable().await;
+                    } else {
+                        // Without new keys the commands still have to time out.
+                        retry_bot.expire_undecryptable().await;
+                    }
+                }
+            })
+            .await;
+    });
+
+    shutdown::signal_received().await;
+
+    // New commands are ignored from here on.
+    let timeout = Duration::from_secs(shared_config.get().shutdown.drain_timeout_secs);
+    let drained = shutdown.drain(timeout).await;
+    if !drained {
+        let interrupted = jobs.interrupt_unfinished("interrupted by shutdown").await;
+        warn!("Marked {} unfinished job(s) as failed", interrupted);
+    }
+    // Jobs may have changed room state since the last sync stored it.
+    for room_id in client.joined_rooms().read().await.keys() {
+        if let Err(e) = client.base_client.store_room_state(room_id).await {
+            warn!("Unable to store the state of {}: {:?}", room_id, e);
+        }
+    }
+    shutdown.remove_temp_files();
+    info!("Shut down");
+
+    Ok(if drained {
+        0
+    } else {
+        shutdown::EXIT_JOBS_INTERRUPTED
+    })
+}
 
+async fn logout(cli: &Cli, config: &Config) -> Result<(), matrix_sdk::Error> {
+    let store = store_dir(cli, config);
+    let client = match restore_session(cli, &store, config.homeserver.clone()).await? {
+        Some(client) => client,
+        None => fail("Not logged in"),
+    };
+    client.send(logout::Request {}).await?;
+    fs::remove_file(store.join("session.json")).unwrap();
+
+    info!("Logged out");
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), matrix_sdk::Error> {
-    let subscriber = FmtSubscriber::builder()
-        // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
-        // will be written to stdout.
-        .with_max_level(Level::DEBUG)
-        // completes the builder.
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-
-    let (homeserver_url, username, password) =
-        match (env::args().nth(1), env::args().nth(2), env::args().nth(3)) {
-            (Some(a), Some(b), Some(c)) => (a, b, c),
-            _ => {
-                eprintln!(
-                    "Usage: {} <homeserver_url> <username> <password>",
-                    env::args().next().unwrap()
-                );
-                exit(1)
-            }
-        };
+    let cli = Cli::from_args();
+    let config = Config::load(&cli.config, &cli.env_vars());
+    // An invalid config is still reported through the default logger.
+    match &config {
+        Ok(config) => logging::init(&config.logging),
+        Err(_) => logging::init(&LoggingConfig::default()),
+    }
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => fail(&format!("Invalid config: {}", e)),
+    };
 
-    login_and_sync(homeserver_url, username, password).await?;
+    match &cli.command {
+        Command::Run(args) => {
+            let code = run(&cli, config, args).await?;
+            if code != 0 {
+                exit(code);
+            }
+        }
+        Command::Login(args) => {
+            let (client, _) = login(&cli, &config, args).await?;
+            if let Some(user_id) = client.user_id().await {
+                info!("Logged in as {}", user_id);
+            }
+        }
+        Command::Logout => logout(&cli, &config).await?,
+        Command::CheckConfig => {
+            println!("{}", serde_yaml::to_string(&config.redacted()).unwrap());
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,65 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on, e.g. `127.0.0.1:9090`. Disabled if unset.
+    pub listen: Option<String>,
+}
+
+lazy_static! {
+    pub static ref ARCHIVE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "ipfs_bot_archive_requests_total",
+        "Archive requests by outcome and media type",
+        &["outcome", "media_type"]
+    )
+    .unwrap();
+    pub static ref DOWNLOADED_BYTES: IntCounter = register_int_counter!(
+        "ipfs_bot_downloaded_bytes_total",
+        "Bytes downloaded from the media repository"
+    )
+    .unwrap();
+    pub static ref ADDED_BYTES: IntCounter =
+        register_int_counter!("ipfs_bot_added_bytes_total", "Bytes added to IPFS").unwrap();
+    pub static ref STEP_DURATION: HistogramVec = register_histogram_vec!(
+        "ipfs_bot_step_duration_seconds",
+        "Duration of the download, add and pin steps including retries",
+        &["step"],
+        vec![0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "ipfs_bot_queue_depth",
+        "Commands waiting for consent or a room key"
+    )
+    .unwrap();
+    pub static ref ACTIVE_JOBS: IntGauge =
+        register_int_gauge!("ipfs_bot_active_jobs", "Archive jobs currently running").unwrap();
+    pub static ref IPFS_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "ipfs_bot_ipfs_api_errors_total",
+        "Failed calls to the IPFS API by call",
+        &["call"]
+    )
+    .unwrap();
+    pub static ref MATRIX_SEND_ERRORS: IntCounter = register_int_counter!(
+        "ipfs_bot_matrix_send_errors_total",
+        "Messages, edits and reactions the homeserver didn't accept"
+    )
+    .unwrap();
+    pub static ref ROOMS_JOINED: IntGauge =
+        register_int_gauge!("ipfs_bot_rooms_joined", "Rooms the bot is in").unwrap();
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
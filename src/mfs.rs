@@ -0,0 +1,155 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Error;
+use crate::ipfs::IpfsApi;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MfsConfig {
+    /// Copy archived files into the Mutable File System of the node that added them.
+    pub enabled: bool,
+    /// Absolute MFS path of a file. `{room}`, `{yyyy}`, `{mm}`, `{dd}` and `{filename}`
+    /// are replaced. Everything up to the part with `{room}` is the directory of the room.
+    pub path_template: String,
+}
+
+impl Default for MfsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path_template: "/matrix/{room}/{yyyy}-{mm}/{filename}".to_string(),
+        }
+    }
+}
+
+impl MfsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.path_template.starts_with('/') {
+            return Err("path_template: has to be an absolute path".to_string());
+        }
+        let parts: Vec<&str> = self.path_template.split('/').collect();
+        let last = parts.len() - 1;
+        match parts.iter().position(|part| part.contains("{room}")) {
+            Some(i) if i < last => {
+                if parts[..=i].join("/").replace("{room}", "").contains('{') {
+                    return Err("path_template: the room directory may only use {room}".to_string());
+                }
+            }
+            _ => return Err("path_template: needs a directory with {room} in it".to_string()),
+        }
+        if !parts[last].contains("{filename}") {
+            return Err("path_template: has to end with {filename}".to_string());
+        }
+        Ok(())
+    }
+
+    /// Where a file archived at `time` goes.
+    pub fn path(&self, room: &str, filename: &str, time: DateTime<Utc>) -> String {
+        self.path_template
+            .replace("{room}", &path_safe(room))
+            .replace("{yyyy}", &time.format("%Y").to_string())
+            .replace("{mm}", &time.format("%m").to_string())
+            .replace("{dd}", &time.format("%d").to_string())
+            .replace("{filename}", &path_safe(filename))
+    }
+
+    /// The directory holding everything archived in `room`.
+    pub fn room_dir(&self, room: &str) -> String {
+        let parts: Vec<&str> = self.path_template.split('/').collect();
+        let end = parts
+            .iter()
+            .position(|part| part.contains("{room}"))
+            .unwrap_or(parts.len() - 1);
+        parts[..=end].join("/").replace("{room}", &path_safe(room))
+    }
+}
+
+/// Slashes would nest directories.
+fn path_safe(name: &str) -> String {
+    name.replace('/', "_")
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+/// Copies `cid` to `path`, creating the directories on the way. If `path` is taken,
+/// `job` is put in front of the file name. Returns where the file ended up.
+pub async fn copy(client: &IpfsApi, cid: &str, path: &str, job: u64) -> Result<String, Error> {
+    let dir = parent(path);
+    client.files_mkdir(dir).await?;
+    let path = if client.files_stat(path).await.is_ok() {
+        let name = path[dir.len()..].trim_start_matches('/');
+        format!("{}/{}-{}", dir.trim_end_matches('/'), job, name)
+    } else {
+        path.to_string()
+    };
+    client.files_cp(&format!("/ipfs/{}", cid), &path).await?;
+    Ok(path)
+}
+
+/// Removes the copy at `path`. The node may drop the file once it is neither in MFS nor pinned.
+pub async fn remove(client: &IpfsApi, path: &str) -> Result<(), Error> {
+    client.files_rm(path).await
+}
+
+/// The current CID of the directory at `path`.
+pub async fn root(client: &IpfsApi, path: &str) -> Result<String, Error> {
+    client.files_stat(path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn fills_in_the_path_template() {
+        let config = MfsConfig::default();
+        let time = Utc.ymd(2020, 7, 3).and_hms(12, 0, 0);
+        assert_eq!(
+            config.path("!room:example.com", "cat.jpg", time),
+            "/matrix/!room:example.com/2020-07/cat.jpg"
+        );
+        assert_eq!(
+            config.path("!room:example.com", "a/b.jpg", time),
+            "/matrix/!room:example.com/2020-07/a_b.jpg"
+        );
+    }
+
+    #[test]
+    fn room_dir_ends_at_the_room() {
+        let config = MfsConfig {
+            enabled: true,
+            path_template: "/archive/rooms/{room}/{yyyy}/{mm}/{dd}/{filename}".to_string(),
+        };
+        assert_eq!(
+            config.room_dir("!room:example.com"),
+            "/archive/rooms/!room:example.com"
+        );
+    }
+
+    #[test]
+    fn validates_the_path_template() {
+        let template = |path_template: &str| MfsConfig {
+            enabled: true,
+            path_template: path_template.to_string(),
+        };
+        assert!(MfsConfig::default().validate().is_ok());
+        assert!(template("matrix/{room}/{filename}").validate().is_err());
+        assert!(template("/matrix/{filename}").validate().is_err());
+        assert!(template("/matrix/{room}/{yyyy}").validate().is_err());
+        assert!(template("/{yyyy}/{room}/{filename}").validate().is_err());
+        assert!(template("/matrix/{room}-{filename}").validate().is_err());
+    }
+
+    #[test]
+    fn parent_of_a_path() {
+        assert_eq!(parent("/matrix/room/file"), "/matrix/room");
+        assert_eq!(parent("/file"), "/");
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::ipfs::IpfsApi;
+use crate::jobs::{JobHistory, JobStatus};
+use crate::reload::SharedConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeRole {
+    /// Adds files if the nodes before it are down.
+    Primary,
+    /// Pins files added elsewhere. Only adds files if no primary is reachable.
+    Replica,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IpfsNodeConfig {
+    /// URL of the IPFS HTTP API of the node.
+    pub api: String,
+    pub role: NodeRole,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// How many replica nodes should pin each file. All of them if unset.
+    pub replicas: Option<usize>,
+    /// How often replicas that failed to pin are tried again in seconds.
+    pub retry_interval_secs: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            replicas: None,
+            retry_interval_secs: 5 * 60,
+        }
+    }
+}
+
+pub struct IpfsNode {
+    pub api: String,
+    pub role: NodeRole,
+    pub client: IpfsApi,
+}
+
+/// The IPFS nodes the bot talks to, `ipfs_api` first. Changes need a restart.
+pub struct IpfsNodes {
+    nodes: Vec<IpfsNode>,
+}
+
+impl IpfsNodes {
+    pub fn new(config: &Config, http_client: &reqwest::Client) -> Self {
+        let primary = IpfsNodeConfig {
+            api: config.ipfs_api.clone(),
+            role: NodeRole::Primary,
+        };
+        let nodes = std::iter::once(&primary)
+            .chain(config.ipfs_nodes.iter())
+            .map(|node| {
+                // The credentials only belong to `ipfs_api`.
+                let auth = if node.api == config.ipfs_api {
+                    config.ipfs_api_auth.clone()
+                } else {
+                    None
+                };
+                IpfsNode {
+                    api: node.api.clone(),
+                    role: node.role,
+                    client: IpfsApi::new(http_client.clone(), &node.api, auth),
+                }
+            })
+            .collect();
+        Self { nodes }
+    }
+
+    /// The node `ipfs_api` points to.
+    pub fn primary(&self) -> &IpfsNode {
+        &self.nodes[0]
+    }
+
+    pub fn get(&self, api: &str) -> Option<&IpfsNode> {
+        self.nodes.iter().find(|node| node.api == api)
+    }
+
+    /// The nodes in the order they are tried for adding a file.
+    pub fn add_order(&self) -> Vec<&IpfsNode> {
+        let primaries = self.nodes.iter().filter(|n| n.role == NodeRole::Primary);
+        let replicas = self.nodes.iter().filter(|n| n.role == NodeRole::Replica);
+        primaries.chain(replicas).collect()
+    }
+
+    pub fn replicas(&self) -> Vec<&IpfsNode> {
+        self.nodes
+            .iter()
+            .filter(|node| node.role == NodeRole::Replica)
+            .collect()
+    }
+
+    /// How many replicas should pin each file.
+    pub fn wanted_replicas(&self, config: &ReplicationConfig) -> usize {
+        let available = self.replicas().len();
+        config.replicas.unwrap_or(available).min(available)
+    }
+
+    /// Pins `hash` on replicas until `wanted` of them have it, skipping `done`.
+    /// Returns the replicas that pinned it and the ones that failed.
+    pub async fn replicate(
+        &self,
+        hash: &str,
+        wanted: usize,
+        done: &[String],
+    ) -> (Vec<String>, Vec<String>) {
+        let mut pinned = done.to_vec();
+        let mut failed = Vec::new();
+        for node in self.replicas() {
+            if pinned.len() >= wanted {
+                break;
+            }
+            if pinned.contains(&node.api) {
+                continue;
+            }
+            match node.client.pin_add(hash).await {
+                Ok(_) => pinned.push(node.api.clone()),
+                Err(e) => {
+                    warn!("Unable to pin {} on {}: {}", hash, node.api, e);
+                    failed.push(node.api.clone());
+                }
+            }
+        }
+        if pinned.len() >= wanted {
+            failed.clear();
+        }
+        (pinned, failed)
+    }
+}
+
+/// Periodically pins files on replicas that lagged behind when the file was archived.
+pub fn spawn_replication(nodes: Arc<IpfsNodes>, jobs: Arc<JobHistory>, config: SharedConfig) {
+    tokio::spawn(async move {
+        loop {
+            let replication = config.get().replication.clone();
+            tokio::time::delay_for(Duration::from_secs(replication.retry_interval_secs.max(1)))
+                .await;
+
+            let wanted = nodes.wanted_replicas(&replication);
+            for record in jobs.records().await {
+                if record.status != JobStatus::Done
+                    || record.unpinned.is_some()
+                    || record.pending_replicas.is_empty()
+                {
+                    continue;
+                }
+                let hash = match &record.hash {
+                    Some(hash) => hash,
+                    None => continue,
+                };
+                let (replicas, pending) = nodes.replicate(hash, wanted, &record.replicas).await;
+                if pending.is_empty() {
+                    info!("{} is now pinned on {} replica(s)", hash, replicas.len());
+                }
+                jobs.set_replicas(record.id, replicas, pending).await;
+            }
+        }
+    });
+}
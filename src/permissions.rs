@@ -0,0 +1,159 @@
+use std::fmt;
+
+use matrix_sdk::{events::EventType, identifiers::UserId, Room};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Who may run a command.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Anyone,
+    /// The sender of the media the command refers to. Moderators and admins are allowed too.
+    MediaSender,
+    /// Members with at least `policies.moderator_power_level`. Admins are allowed too.
+    Moderator,
+    /// Users listed in `admin.users` or, without any, room admins of the admin room.
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::Anyone => write!(f, "anyone"),
+            Permission::MediaSender => write!(f, "the sender of the media and moderators"),
+            Permission::Moderator => write!(f, "moderators"),
+            Permission::Admin => write!(f, "bot admins"),
+        }
+    }
+}
+
+impl Permission {
+    /// Whether `user_id` has this permission in `room`. `media_sender` is only needed
+    /// for `MediaSender`, without it only moderators and admins are allowed.
+    pub fn allows(
+        self,
+        room: &Room,
+        user_id: &UserId,
+        media_sender: Option<&UserId>,
+        config: &Config,
+    ) -> bool {
+        let is_admin = config.admin.users.iter().any(|u| *u == user_id.to_string())
+            || config.admin.is_admin(room, user_id);
+        let is_moderator =
+            is_admin || user_level(room, user_id) >= config.policies.moderator_power_level;
+        match self {
+            Permission::Anyone => true,
+            Permission::MediaSender => media_sender == Some(user_id) || is_moderator,
+            Permission::Moderator => is_moderator,
+            Permission::Admin => is_admin,
+        }
+    }
+}
+
+/// The power level of `user_id` in the room, following the `m.room.power_levels` defaults.
+pub fn user_level(room: &Room, user_id: &UserId) -> i64 {
+    let member_level = room
+        .joined_members
+        .get(user_id)
+        .and_then(|member| member.power_level);
+    match (member_level, &room.power_levels) {
+        (Some(level), _) => i64::from(level),
+        (None, Some(levels)) => i64::from(levels.users_default),
+        // Without power levels the creator has 100 and everybody else 0.
+        (None, None) => {
+            if room.creator.as_ref() == Some(user_id) {
+                100
+            } else {
+                0
+            }
+        }
+    }
+}
+
+/// The power level required to send a state event of the given type.
+pub fn state_event_level(room: &Room, event_type: &str) -> i64 {
+    match &room.power_levels {
+        Some(levels) => levels
+            .events
+            .get(&EventType::Custom(event_type.to_string()))
+            .map(|level| i64::from(*level))
+            .unwrap_or_else(|| i64::from(levels.state_default)),
+        None => 0,
+    }
+}
+
+/// Whether `user_id` may send a state event of the given type into the room.
+pub fn can_send_state(room: &Room, user_id: &UserId, event_type: &str) -> bool {
+    user_level(room, user_id) >= state_event_level(room, event_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use matrix_sdk::identifiers::RoomId;
+
+    use super::*;
+
+    fn user(id: &str) -> UserId {
+        UserId::try_from(id).unwrap()
+    }
+
+    /// A room without power levels, created by `@creator:example.com`.
+    fn room() -> Room {
+        let room_id = RoomId::try_from("!room:example.com").unwrap();
+        let mut room = Room::new(&room_id, &user("@bot:example.com"));
+        room.creator = Some(user("@creator:example.com"));
+        room
+    }
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("ipfs_api: \"http://localhost:5001\"\n{}", yaml)).unwrap()
+    }
+
+    #[test]
+    fn only_the_creator_has_power_without_power_levels() {
+        let room = room();
+        assert_eq!(user_level(&room, &user("@creator:example.com")), 100);
+        assert_eq!(user_level(&room, &user("@alice:example.com")), 0);
+    }
+
+    #[test]
+    fn moderators_may_act_for_the_media_sender() {
+        let room = room();
+        let config = config("");
+        let creator = user("@creator:example.com");
+        let alice = user("@alice:example.com");
+        let bob = user("@bob:example.com");
+
+        assert!(Permission::Anyone.allows(&room, &bob, None, &config));
+        assert!(Permission::MediaSender.allows(&room, &alice, Some(&alice), &config));
+        assert!(!Permission::MediaSender.allows(&room, &bob, Some(&alice), &config));
+        assert!(!Permission::MediaSender.allows(&room, &bob, None, &config));
+        assert!(Permission::MediaSender.allows(&room, &creator, Some(&alice), &config));
+        assert!(Permission::Moderator.allows(&room, &creator, None, &config));
+        assert!(!Permission::Moderator.allows(&room, &alice, None, &config));
+    }
+
+    #[test]
+    fn admins_come_from_the_config_or_the_admin_room() {
+        let room = room();
+        let creator = user("@creator:example.com");
+        let alice = user("@alice:example.com");
+
+        // Outside of the admin room power doesn't make anybody an admin.
+        let config_without_admins = config("");
+        assert!(!Permission::Admin.allows(&room, &creator, None, &config_without_admins));
+
+        let listed = config("admin:\n  users: [\"@alice:example.com\"]");
+        assert!(Permission::Admin.allows(&room, &alice, None, &listed));
+        assert!(Permission::Moderator.allows(&room, &alice, None, &listed));
+        assert!(!Permission::Admin.allows(&room, &creator, None, &listed));
+
+        let admin_room = config("admin:\n  room: \"!room:example.com\"");
+        assert!(Permission::Admin.allows(&room, &creator, None, &admin_room));
+        assert!(!Permission::Admin.allows(&room, &alice, None, &admin_room));
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use matrix_sdk::{
+    identifiers::{EventId, RoomId},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::metrics;
+use crate::reply::{self, ReplyTarget};
+use crate::send_raw_event;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProgressConfig {
+    /// Post a "working on it" notice and keep editing it while archiving.
+    pub enabled: bool,
+    /// Minimum time between two edits of the notice in seconds.
+    pub update_interval_secs: u64,
+}
+
+impl Default for ProgressConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            update_interval_secs: 5,
+        }
+    }
+}
+
+/// A notice in the room that gets edited (`m.replace`) as an archive job advances.
+pub struct Progress {
+    client: Client,
+    room_id: RoomId,
+    notice: Option<EventId>,
+    /// Edits of the notice are skipped in encrypted rooms.
+    encrypted: bool,
+    update_interval: Duration,
+    last_update: Mutex<Instant>,
+}
+
+impl Progress {
+    /// Posts the initial notice. If progress notices are disabled nothing is sent.
+    /// In encrypted rooms the notice is never edited, as edits can only be sent unencrypted.
+    pub async fn start(
+        client: Client,
+        room_id: RoomId,
+        config: &ProgressConfig,
+        filename: &str,
+        reply: &ReplyTarget,
+    ) -> Self {
+        let encrypted = match client.get_joined_room(&room_id).await {
+            Some(room) => room.read().await.is_encrypted(),
+            None => false,
+        };
+        let notice = if config.enabled {
+            reply::send_notice(
+                &client,
+                &room_id,
+                format!("Working on it: archiving {} to IPFS…", filename),
+                reply,
+            )
+            .await
+        } else {
+            None
+        };
+
+        Self {
+            client,
+            room_id,
+            notice,
+            encrypted,
+            update_interval: Duration::from_secs(config.update_interval_secs),
+            last_update: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reports download progress, rate limited to `update_interval_secs`.
+    pub async fn downloading(&self, received: u64, total: Option<u64>) {
+        if self.notice.is_none() || !self.should_update() {
+            return;
+        }
+        let body = match total {
+            Some(total) if total > 0 => format!("Downloading… {}%", received * 100 / total),
+            _ => format!("Downloading… {} bytes", received),
+        };
+        self.edit(body).await;
+    }
+
+    pub async fn adding(&self) {
+        self.edit("Adding to IPFS…".to_string()).await;
+    }
+
+    pub async fn pinning(&self) {
+        self.edit("Pinning…".to_string()).await;
+    }
+
+    pub async fn replicating(&self) {
+        self.edit("Pinning on replicas…".to_string()).await;
+    }
+
+    pub async fn pinning_remotely(&self) {
+        self.edit("Pinning on the pinning service…".to_string())
+            .await;
+    }
+
+    /// Replaces the notice with the final result.
+    /// Returns `false` if there is no notice to edit and the caller has to send its own message.
+    pub async fn finish(&self, body: String) -> bool {
+        if self.notice.is_none() {
+            return false;
+        }
+        self.edit(body).await
+    }
+
+    fn should_update(&self) -> bool {
+        let mut last_update = self.last_update.lock().unwrap();
+        if last_update.elapsed() >= self.update_interval {
+            *last_update = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+
+    async fn edit(&self, body: String) -> bool {
+        let event_id = match &self.notice {
+            Some(event_id) if !self.encrypted => event_id,
+            _ => return false,
+        };
+
+        let content = json!({
+            "msgtype": "m.notice",
+            "body": format!("* {}", body),
+            "m.new_content": {
+                "msgtype": "m.notice",
+                "body": body,
+            },
+            "m.relates_to": {
+                "rel_type": "m.replace",
+                "event_id": event_id.to_string(),
+            },
+        });
+
+        let resp = self
+            .client
+            .send(send_raw_event::Request {
+                room_id: self.room_id.clone(),
+                event_type: "m.room.message".to_string(),
+                txn_id: Uuid::new_v4().to_string(),
+                content,
+            })
+            .await;
+
+        match resp {
+            Ok(_) => true,
+            Err(e) => {
+                metrics::MATRIX_SEND_ERRORS.inc();
+                warn!("Unable to edit progress notice: {:?}", e);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use matrix_sdk::{
+    api::r0::redact::redact_event,
+    identifiers::{EventId, RoomId},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::jobs::JobStatus;
+use crate::metrics;
+use crate::send_raw_event;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReactionsConfig {
+    /// React to `!ipfs` commands with the state of the job.
+    pub enabled: bool,
+    pub queued: String,
+    pub in_progress: String,
+    pub done: String,
+    pub failed: String,
+    /// Per room overrides of `enabled`, keyed by room id.
+    pub rooms: HashMap<String, bool>,
+}
+
+impl Default for ReactionsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            queued: "⏳".to_string(),
+            in_progress: "⚙️".to_string(),
+            done: "✅".to_string(),
+            failed: "❌".to_string(),
+            rooms: HashMap::new(),
+        }
+    }
+}
+
+impl ReactionsConfig {
+    pub fn enabled_in(&self, room_id: &RoomId) -> bool {
+        self.rooms
+            .get(&room_id.to_string())
+            .copied()
+            .unwrap_or(self.enabled)
+    }
+
+    fn key(&self, status: JobStatus) -> &str {
+        match status {
+            JobStatus::Queued => &self.queued,
+            JobStatus::InProgress => &self.in_progress,
+            JobStatus::Done => &self.done,
+            JobStatus::Failed => &self.failed,
+        }
+    }
+}
+
+/// The reaction the bot currently shows on a command message.
+pub struct StatusReactions {
+    client: Client,
+    room_id: RoomId,
+    target: EventId,
+    config: Option<ReactionsConfig>,
+    current: Mutex<Option<EventId>>,
+}
+
+impl StatusReactions {
+    /// `enabled` overrides the config, e.g. for rooms that chose reactions as their reply style.
+    pub fn new(
+        client: Client,
+        room_id: RoomId,
+        target: EventId,
+        config: &ReactionsConfig,
+        enabled: bool,
+    ) -> Self {
+        let config = if enabled { Some(config.clone()) } else { None };
+        Self {
+            client,
+            room_id,
+            target,
+            config,
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Replaces the previous status reaction with the one for `status`.
+    pub async fn set(&self, status: JobStatus) {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+
+        let previous = self.current.lock().unwrap().take();
+        if let Some(previous) = previous {
+            let resp = self
+                .client
+                .send(redact_event::Request {
+                    room_id: self.room_id.clone(),
+                    event_id: previous,
+                    txn_id: Uuid::new_v4().to_string(),
+                    reason: None,
+                })
+                .await;
+            if let Err(e) = resp {
+                metrics::MATRIX_SEND_ERRORS.inc();
+                warn!("Unable to remove status reaction: {:?}", e);
+            }
+        }
+
+        let content = json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": self.target.to_string(),
+                "key": config.key(status),
+            },
+        });
+        let resp = self
+            .client
+            .send(send_raw_event::Request {
+                room_id: self.room_id.clone(),
+                event_type: "m.reaction".to_string(),
+                txn_id: Uuid::new_v4().to_string(),
+                content,
+            })
+            .await;
+        match resp {
+            Ok(resp) => *self.current.lock().unwrap() = Some(resp.event_id),
+            Err(e) => {
+                metrics::MATRIX_SEND_ERRORS.inc();
+                warn!("Unable to send status reaction: {:?}", e);
+            }
+        }
+    }
+}
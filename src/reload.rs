@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+use crate::admin::Alerts;
+use crate::config::Config;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReloadConfig {
+    /// Reload the config when the file changes. SIGHUP always triggers a reload.
+    pub watch_file: bool,
+    /// How often the modification time of the config file is checked in seconds.
+    pub poll_interval_secs: u64,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        Self {
+            watch_file: true,
+            poll_interval_secs: 5,
+        }
+    }
+}
+
+/// The config shared by all handlers. A reload swaps it as a whole, so a handler
+/// holding a snapshot from `get` never sees a half updated config.
+#[derive(Clone)]
+pub struct SharedConfig {
+    path: PathBuf,
+    /// Environment variables of the CLI, which aren't config overrides.
+    cli_env_vars: Vec<String>,
+    current: Arc<RwLock<Arc<Config>>>,
+}
+
+impl SharedConfig {
+    pub fn new(path: PathBuf, cli_env_vars: Vec<String>, config: Config) -> Self {
+        Self {
+            path,
+            cli_env_vars,
+            current: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Loads and validates the config file and swaps it in.
+    /// On errors the previous config stays active.
+    pub fn reload(&self) -> Result<(), String> {
+        let new = Config::load(&self.path, &self.cli_env_vars)?;
+        let old = self.get();
+
+        // These are only used while starting up.
+        if new.homeserver != old.homeserver
+            || new.credentials != old.credentials
+            || new.store_path != old.store_path
+            || new.ipfs_api != old.ipfs_api
+            || new.ipfs_api_auth != old.ipfs_api_auth
+            || new.ipfs_nodes != old.ipfs_nodes
+            || new.logging != old.logging
+            || new.metrics.listen != old.metrics.listen
+            || new.health.listen != old.health.listen
+            || new.audit.path != old.audit.path
+        {
+            warn!(
+                "homeserver, credentials, store_path, the IPFS API and nodes, logging, the \
+                 metrics and health listen addresses and the audit log path only change after \
+                 a restart"
+            );
+        }
+
+        *self.current.write().unwrap() = Arc::new(new);
+        Ok(())
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    /// Reloads the config and alerts the admin room if that fails.
+    async fn reload_and_log(&self, reason: &str, alerts: &Alerts) {
+        match self.reload() {
+            Ok(()) => info!("Reloaded config after {}", reason),
+            Err(e) => {
+                alerts
+                    .send(format!(
+                        "Keeping the previous config, reload after {} failed: {}",
+                        reason, e
+                    ))
+                    .await
+            }
+        }
+    }
+
+    /// Reloads the config on SIGHUP and, if enabled, whenever the file changes.
+    pub fn spawn_watchers(&self, alerts: Alerts) {
+        let shared = self.clone();
+        let hangup_alerts = alerts.clone();
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    warn!("Unable to listen for SIGHUP: {}", e);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                shared.reload_and_log("SIGHUP", &hangup_alerts).await;
+            }
+        });
+
+        let reload = self.get().reload.clone();
+        if !reload.watch_file {
+            return;
+        }
+        let shared = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = shared.modified();
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(reload.poll_interval_secs.max(1)));
+            loop {
+                interval.tick().await;
+                let modified = shared.modified();
+                if modified != last_modified {
+                    last_modified = modified;
+                    shared
+                        .reload_and_log("a change of the config file", &alerts)
+                        .await;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,239 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::errors::Error;
+
+/// How the pinning service is used next to the local node.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemotePinMode {
+    /// Keep the local pin as well. A failing service only shows up in the reply.
+    Additional,
+    /// Remove the local pin once the service pinned the content. A failing service fails the job.
+    Only,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemotePinningConfig {
+    /// Base URL of a service implementing the IPFS Pinning Service API. Disabled if unset.
+    pub endpoint: Option<String>,
+    /// Bearer token for the service.
+    pub token: Option<String>,
+    pub mode: RemotePinMode,
+    /// How often the status of a pin request is checked in seconds.
+    pub poll_interval_secs: u64,
+    /// How long to wait for the service to finish pinning in seconds.
+    pub timeout_secs: u64,
+}
+
+impl Default for RemotePinningConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            token: None,
+            mode: RemotePinMode::Additional,
+            poll_interval_secs: 5,
+            timeout_secs: 10 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PinState {
+    Queued,
+    Pinning,
+    Pinned,
+    Failed,
+}
+
+/// The `PinStatus` object of the Pinning Service API, reduced to what the bot needs.
+#[derive(Debug, Deserialize)]
+struct PinStatus {
+    requestid: String,
+    status: PinState,
+}
+
+/// A pin request at the pinning service as stored with the job.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemotePin {
+    pub request_id: String,
+    /// The last known state, `queued` or `pinning` if the service took too long.
+    pub status: PinState,
+    /// The local pin was removed once the service pinned the content.
+    #[serde(default)]
+    pub replaced_local: bool,
+}
+
+fn request(
+    http_client: &reqwest::Client,
+    config: &RemotePinningConfig,
+    method: reqwest::Method,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, Error> {
+    let endpoint = config
+        .endpoint
+        .as_ref()
+        .ok_or_else(|| Error::PinningService("no pinning service configured".to_string()))?;
+    let url = format!("{}/pins{}", endpoint.trim_end_matches('/'), path);
+    let request = http_client.request(method, &url);
+    Ok(match &config.token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    })
+}
+
+impl RemotePin {
+    /// The service is still working on it.
+    pub fn is_pending(&self) -> bool {
+        self.status == PinState::Queued || self.status == PinState::Pinning
+    }
+}
+
+impl From<PinStatus> for RemotePin {
+    fn from(status: PinStatus) -> Self {
+        Self {
+            request_id: status.requestid,
+            status: status.status,
+            replaced_local: false,
+        }
+    }
+}
+
+/// Asks the service to pin `cid` and returns the state it reported right away.
+pub async fn pin(
+    http_client: &reqwest::Client,
+    config: &RemotePinningConfig,
+    cid: &str,
+    name: &str,
+) -> Result<RemotePin, Error> {
+    let status: PinStatus = request(http_client, config, reqwest::Method::POST, "")?
+        .json(&json!({ "cid": cid, "name": name }))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(service_error)?
+        .json()
+        .await
+        .map_err(service_error)?;
+    Ok(status.into())
+}
+
+/// Polls the pin request until it is pinned, failed or the timeout ran out.
+pub async fn wait(
+    http_client: &reqwest::Client,
+    config: &RemotePinningConfig,
+    mut remote: RemotePin,
+) -> RemotePin {
+    let deadline = Instant::now() + Duration::from_secs(config.timeout_secs);
+    let path = format!("/{}", remote.request_id);
+    while remote.is_pending() && Instant::now() < deadline {
+        tokio::time::delay_for(Duration::from_secs(config.poll_interval_secs.max(1))).await;
+        // The request was accepted, so a failing status check isn't the end of it.
+        let get = match request(http_client, config, reqwest::Method::GET, &path) {
+            Ok(get) => get,
+            Err(_) => break,
+        };
+        if let Ok(resp) = get.send().await.and_then(|r| r.error_for_status()) {
+            if let Ok(current) = resp.json::<PinStatus>().await {
+                remote.status = current.status;
+            }
+        }
+    }
+    remote
+}
+
+/// Removes the pin request from the service.
+pub async fn unpin(
+    http_client: &reqwest::Client,
+    config: &RemotePinningConfig,
+    request_id: &str,
+) -> Result<(), Error> {
+    let path = format!("/{}", request_id);
+    request(http_client, config, reqwest::Method::DELETE, &path)?
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(service_error)?;
+    Ok(())
+}
+
+fn service_error(e: reqwest::Error) -> Error {
+    Error::PinningService(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server};
+
+    use super::*;
+
+    /// Answers like a pinning service that queues the request and has it pinned on the first check.
+    async fn mock_service(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let authorized = req
+            .headers()
+            .get("authorization")
+            .map_or(false, |value| value == "Bearer secret");
+        let (status, body) = match (req.method(), req.uri().path()) {
+            _ if !authorized => (401, ""),
+            (&Method::POST, "/pins") => (202, r#"{"requestid":"r1","status":"queued"}"#),
+            (&Method::GET, "/pins/r1") => (200, r#"{"requestid":"r1","status":"pinned"}"#),
+            (&Method::DELETE, "/pins/r1") => (202, ""),
+            _ => (404, ""),
+        };
+        Ok(Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    fn mock_config(token: &str) -> RemotePinningConfig {
+        let make_service =
+            make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(mock_service)) });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        RemotePinningConfig {
+            endpoint: Some(endpoint),
+            token: Some(token.to_string()),
+            poll_interval_secs: 1,
+            timeout_secs: 10,
+            ..RemotePinningConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn pins_and_polls_until_pinned() {
+        let config = mock_config("secret");
+        let http_client = reqwest::Client::new();
+
+        let remote = pin(&http_client, &config, "bafy", "cat.jpg").await.unwrap();
+        assert_eq!(remote.request_id, "r1");
+        assert!(remote.is_pending());
+
+        let remote = wait(&http_client, &config, remote).await;
+        assert_eq!(remote.status, PinState::Pinned);
+
+        unpin(&http_client, &config, "r1").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_a_rejected_token() {
+        let config = mock_config("wrong");
+        let http_client = reqwest::Client::new();
+
+        match pin(&http_client, &config, "bafy", "cat.jpg").await {
+            Err(Error::PinningService(_)) => {}
+            other => panic!(
+                "expected a pinning service error, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::{
+    api::r0::keys::claim_keys,
+    events::room::message::{
+        FileMessageEventContent, InReplyTo, MessageEventContent, NoticeMessageEventContent,
+        RelatesTo,
+    },
+    identifiers::{EventId, RoomId},
+    Client,
+};
+use serde_json::{json, Value};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::metrics;
+use crate::send_raw_event;
+
+/// A `m.thread` relation as found in the raw `m.relates_to` of an event.
+#[derive(Clone, Debug)]
+pub struct ThreadRelation {
+    /// The event that started the thread.
+    pub root: EventId,
+    /// `true` if `m.in_reply_to` is only there for clients without thread support.
+    pub is_falling_back: bool,
+}
+
+/// Extracts the thread relation from a raw event (encrypted events keep `m.relates_to` in clear).
+pub fn thread_relation(raw_event: &Value) -> Option<ThreadRelation> {
+    let relates_to = raw_event.get("content")?.get("m.relates_to")?;
+    if relates_to.get("rel_type")?.as_str()? != "m.thread" {
+        return None;
+    }
+    let root = relates_to.get("event_id")?.as_str()?;
+    Some(ThreadRelation {
+        root: EventId::try_from(root).ok()?,
+        is_falling_back: relates_to
+            .get("is_falling_back")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+    })
+}
+
+/// Extracts the event a raw event replies to. Encrypted events keep `m.relates_to` in clear,
+/// so the decrypted content doesn't have it.
+pub fn reply_relation(raw_event: &Value) -> Option<EventId> {
+    let in_reply_to = raw_event
+        .get("content")?
+        .get("m.relates_to")?
+        .get("m.in_reply_to")?;
+    EventId::try_from(in_reply_to.get("event_id")?.as_str()?).ok()
+}
+
+/// Where the answers of the bot should end up.
+#[derive(Clone, Debug, Default)]
+pub struct ReplyTarget {
+    /// The event the answer replies to.
+    pub in_reply_to: Option<EventId>,
+    /// The thread the answer is posted into.
+    pub thread_root: Option<EventId>,
+}
+
+impl ReplyTarget {
+    /// The reply relation for clients and events without thread support.
+    fn fallback_relates_to(&self) -> Option<RelatesTo> {
+        let event_id = self
+            .in_reply_to
+            .as_ref()
+            .or_else(|| self.thread_root.as_ref())?;
+        Some(RelatesTo {
+            in_reply_to: InReplyTo {
+                event_id: event_id.clone(),
+            },
+        })
+    }
+
+    fn relates_to(&self) -> Option<Value> {
+        match (&self.thread_root, &self.in_reply_to) {
+            (Some(root), in_reply_to) => {
+                // Clients without thread support render this as a reply to the latest event.
+                let (reply_to, is_falling_back) = match in_reply_to {
+                    Some(event_id) => (event_id, false),
+                    None => (root, true),
+                };
+                Some(json!({
+                    "rel_type": "m.thread",
+                    "event_id": root.to_string(),
+                    "is_falling_back": is_falling_back,
+                    "m.in_reply_to": {
+                        "event_id": reply_to.to_string(),
+                    },
+                }))
+            }
+            (None, Some(event_id)) => Some(json!({
+                "m.in_reply_to": {
+                    "event_id": event_id.to_string(),
+                },
+            })),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Sends a notice to the room and returns its event id.
+pub async fn send_notice(
+    client: &Client,
+    room_id: &RoomId,
+    body: String,
+    reply: &ReplyTarget,
+) -> Option<EventId> {
+    let typed = MessageEventContent::Notice(NoticeMessageEventContent {
+        body: body.clone(),
+        format: None,
+        formatted_body: None,
+        relates_to: reply.fallback_relates_to(),
+    });
+    let content = json!({
+        "msgtype": "m.notice",
+        "body": body,
+    });
+    send_message(client, room_id, content, typed, reply).await
+}
+
+/// Sends a file from the media repository to the room and returns the event id.
+pub async fn send_file(
+    client: &Client,
+    room_id: &RoomId,
+    filename: String,
+    url: String,
+    reply: &ReplyTarget,
+) -> Option<EventId> {
+    let typed = MessageEventContent::File(FileMessageEventContent {
+        body: filename.clone(),
+        filename: Some(filename.clone()),
+        info: None,
+        url: Some(url.clone()),
+        file: None,
+    });
+    let content = json!({
+        "msgtype": "m.file",
+        "body": filename,
+        "filename": filename,
+        "url": url,
+    });
+    send_message(client, room_id, content, typed, reply).await
+}
+
+/// Sends `content` as raw event so it can carry a thread relation. Encrypted rooms get the
+/// `typed` content encrypted, with the relation in clear next to the ciphertext.
+async fn send_message(
+    client: &Client,
+    room_id: &RoomId,
+    mut content: Value,
+    typed: MessageEventContent,
+    reply: &ReplyTarget,
+) -> Option<EventId> {
+    let encrypted = match client.get_joined_room(room_id).await {
+        Some(room) => room.read().await.is_encrypted(),
+        None => false,
+    };
+    if encrypted {
+        return match send_encrypted(client, room_id, typed, reply.relates_to()).await {
+            Ok(event_id) => Some(event_id),
+            Err(e) => {
+                metrics::MATRIX_SEND_ERRORS.inc();
+                warn!("Unable to send encrypted message: {:?}", e);
+                None
+            }
+        };
+    }
+
+    if let Some(relates_to) = reply.relates_to() {
+        content["m.relates_to"] = relates_to;
+    }
+
+    let resp = client
+        .send(send_raw_event::Request {
+            room_id: room_id.clone(),
+            event_type: "m.room.message".to_string(),
+            txn_id: Uuid::new_v4().to_string(),
+            content,
+        })
+        .await;
+
+    match resp {
+        Ok(resp) => Some(resp.event_id),
+        Err(e) => {
+            metrics::MATRIX_SEND_ERRORS.inc();
+            warn!("Unable to send message: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Encrypts `typed` the way `Client::room_send` does, which can't add a thread relation.
+/// Relations of encrypted events are sent in clear, so `relates_to` goes next to the ciphertext.
+async fn send_encrypted(
+    client: &Client,
+    room_id: &RoomId,
+    typed: MessageEventContent,
+    relates_to: Option<Value>,
+) -> Result<EventId, matrix_sdk::Error> {
+    let base_client = &client.base_client;
+    let missing_sessions = match client.get_joined_room(room_id).await {
+        Some(room) => {
+            let room = room.read().await;
+            base_client
+                .get_missing_sessions(room.joined_members.keys())
+                .await?
+        }
+        None => Default::default(),
+    };
+    if !missing_sessions.is_empty() {
+        let resp = client
+            .send(claim_keys::Request {
+                timeout: None,
+                one_time_keys: missing_sessions,
+            })
+            .await?;
+        base_client.receive_keys_claim_response(&resp).await?;
+    }
+    if base_client.should_share_group_session(room_id).await {
+        for request in base_client.share_group_session(room_id).await? {
+            client.send(request).await?;
+        }
+    }
+
+    let mut content = serde_json::to_value(base_client.encrypt(room_id, typed).await?)?;
+    if let Some(relates_to) = relates_to {
+        content["m.relates_to"] = relates_to;
+    }
+    let resp = client
+        .send(send_raw_event::Request {
+            room_id: room_id.clone(),
+            event_type: "m.room.encrypted".to_string(),
+            txn_id: Uuid::new_v4().to_string(),
+            content,
+        })
+        .await?;
+    Ok(resp.event_id)
+}
@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+use matrix_sdk::identifiers::RoomId;
+use tracing::{info, warn};
+
+use crate::audit::{AuditAction, AuditEntry, AuditLog};
+use crate::cluster;
+use crate::jobs::{now, JobHistory, JobStatus};
+use crate::mfs;
+use crate::nodes::IpfsNodes;
+use crate::reload::SharedConfig;
+use crate::remote_pin;
+use crate::room_settings::RoomSettingsStore;
+
+/// How often archived media is checked against the retention of its room.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Who shows up as the one that unpinned media after its retention ran out.
+pub const RETENTION_ACTOR: &str = "retention";
+
+/// Periodically unpins media whose room has a `retention_days` setting that ran out.
+pub fn spawn(
+    nodes: Arc<IpfsNodes>,
+    http_client: reqwest::Client,
+    config: SharedConfig,
+    jobs: Arc<JobHistory>,
+    settings: Arc<RoomSettingsStore>,
+    audit: Arc<AuditLog>,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweep(&nodes, &http_client, &config, &jobs, &settings, &audit).await;
+        }
+    });
+}
+
+async fn sweep(
+    nodes: &IpfsNodes,
+    http_client: &reqwest::Client,
+    config: &SharedConfig,
+    jobs: &JobHistory,
+    settings: &RoomSettingsStore,
+    audit: &AuditLog,
+) {
+    let mut expired = Vec::new();
+    // Hashes that are still wanted by another job must stay pinned.
+    let mut kept = HashSet::new();
+
+    for record in jobs.records().await {
+        if record.status != JobStatus::Done || record.unpinned.is_some() {
+            continue;
+        }
+        let (hash, finished) = match (&record.hash, record.finished) {
+            (Some(hash), Some(finished)) => (hash.clone(), finished),
+            _ => continue,
+        };
+        let retention_days = match RoomId::try_from(record.room_id.as_str()) {
+            Ok(room_id) => settings.get(&room_id).await.retention_days,
+            Err(_) => None,
+        };
+        match retention_days {
+            Some(days) if finished + days * 24 * 60 * 60 <= now() => expired.push((record, hash)),
+            _ => {
+                kept.insert(hash);
+            }
+        }
+    }
+
+    let mut unpinned = HashSet::new();
+    for (record, hash) in expired {
+        if let Some(remote) = &record.remote_pin {
+            let current = config.get();
+            let service = &current.remote_pinning;
+            if let Err(e) = remote_pin::unpin(http_client, service, &remote.request_id).await {
+                warn!("Unable to remove pin request {}: {}", remote.request_id, e);
+                continue;
+            }
+        }
+        if let Some(path) = &record.mfs_path {
+            let node = record
+                .added_on
+                .as_ref()
+                .and_then(|api| nodes.get(api))
+                .unwrap_or_else(|| nodes.primary());
+            // The copy would keep the file on the node after it got unpinned.
+            if let Err(e) = mfs::remove(&node.client, path).await {
+                warn!("Unable to remove {} from MFS: {}", path, e);
+            }
+        }
+        let pinned_locally = record
+            .remote_pin
+            .as_ref()
+            .map_or(true, |remote| !remote.replaced_local);
+        if pinned_locally && !kept.contains(&hash) && !unpinned.contains(&hash) {
+            let current = config.get();
+            let result = if record.cluster_pin.is_some() && current.cluster.api.is_some() {
+                cluster::unpin(http_client, &current.cluster, &hash).await
+            } else {
+                let node = record
+                    .added_on
+                    .as_ref()
+                    .and_then(|api| nodes.get(api))
+                    .unwrap_or_else(|| nodes.primary());
+                node.client.pin_rm(&hash).await
+            };
+            if let Err(e) = result {
+                warn!("Unable to unpin {}: {}", hash, e);
+                continue;
+            }
+            for api in &record.replicas {
+                if let Some(replica) = nodes.get(api) {
+                    if let Err(e) = replica.client.pin_rm(&hash).await {
+                        warn!("Unable to unpin {} on {}: {}", hash, api, e);
+                    }
+                }
+            }
+            info!(
+                "Unpinned {} from {} after its retention ran out",
+                hash, record.room_id
+            );
+            unpinned.insert(hash.clone());
+        }
+        let mut entry = AuditEntry::new(AuditAction::Unpin, RETENTION_ACTOR.to_string());
+        entry.automatic = true;
+        entry.room_id = Some(record.room_id.clone());
+        entry.event_id = Some(record.event_id.clone());
+        entry.cid = Some(hash);
+        entry.detail = Some("retention ran out".to_string());
+        audit.record(entry);
+        jobs.mark_unpinned(record.id, RETENTION_ACTOR.to_string())
+            .await;
+    }
+}
@@ -0,0 +1,197 @@
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::errors::Error;
+
+/// Classes of failures that may be retried.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryableError {
+    Timeout,
+    Connect,
+    ServerError,
+    TooManyRequests,
+    Io,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// How often a step is tried in total before giving up.
+    pub max_attempts: u32,
+    /// Delay before the first retry in milliseconds.
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the delay between two attempts in milliseconds.
+    pub max_backoff_ms: u64,
+    /// Factor the delay grows by after every failed attempt.
+    pub multiplier: f64,
+    /// Fraction of the delay that gets randomly added or removed (0.0 - 1.0).
+    pub jitter: f64,
+    /// Which kinds of errors are worth another attempt.
+    pub retry_on: Vec<RetryableError>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            retry_on: vec![
+                RetryableError::Timeout,
+                RetryableError::Connect,
+                RetryableError::ServerError,
+                RetryableError::TooManyRequests,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay to wait after the given (1-based) failed attempt.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1) as i32;
+        let base = (self.initial_backoff_ms as f64 * self.multiplier.powi(exponent))
+            .min(self.max_backoff_ms as f64);
+        let jitter = self.jitter.max(0.0).min(1.0);
+        let factor = if jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - jitter, 1.0 + jitter)
+        } else {
+            1.0
+        };
+        Duration::from_millis((base * factor) as u64)
+    }
+
+    fn is_retryable(&self, error: &Error) -> bool {
+        match error.kind() {
+            Some(kind) => self.retry_on.contains(&kind),
+            None => false,
+        }
+    }
+
+    /// Runs `f` until it succeeds, fails with a non retryable error or `max_attempts` is reached.
+    pub async fn run<T, F, Fut>(&self, step: &'static str, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < max_attempts && self.is_retryable(&e) => {
+                    let delay = self.backoff(attempt);
+                    warn!(
+                        "{} failed (attempt {}/{}), retrying in {:?}: {}",
+                        step, attempt, max_attempts, delay, e
+                    );
+                    tokio::time::delay_for(delay).await;
+                }
+                // Nothing was retried, so there is nothing to add to the error.
+                Err(e) if attempt == 1 => return Err(e),
+                Err(e) => {
+                    return Err(Error::Exhausted {
+                        step,
+                        attempts: attempt,
+                        source: Box::new(e),
+                    })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy(retry_on: Vec<RetryableError>) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 0,
+            jitter: 0.0,
+            retry_on,
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn io_error() -> Error {
+        Error::Io(io::Error::new(io::ErrorKind::Other, "disk full"))
+    }
+
+    #[test]
+    fn classifies_errors() {
+        assert_eq!(io_error().kind(), Some(RetryableError::Io));
+        assert_eq!(Error::IpfsCommand("no link".to_string()).kind(), None);
+        assert_eq!(Error::TooLarge { limit: 1 }.kind(), None);
+        assert_eq!(Error::Crypto("bad key".to_string()).kind(), None);
+
+        let policy = policy(vec![RetryableError::Io]);
+        assert!(policy.is_retryable(&io_error()));
+        assert!(!policy.is_retryable(&Error::TooLarge { limit: 1 }));
+        assert!(!RetryPolicy::default().is_retryable(&io_error()));
+    }
+
+    #[tokio::test]
+    async fn returns_a_non_retryable_error_as_is() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = policy(vec![RetryableError::Io])
+            .run("add", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(Error::TooLarge { limit: 1 })
+            })
+            .await;
+        assert!(matches!(result, Err(Error::TooLarge { limit: 1 })));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = policy(vec![RetryableError::Io])
+            .run("add", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(io_error())
+            })
+            .await;
+        match result {
+            Err(Error::Exhausted {
+                step,
+                attempts: 3,
+                source,
+            }) => {
+                assert_eq!(step, "add");
+                assert!(matches!(*source, Error::Io(_)));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_once_it_succeeds() {
+        let attempts = AtomicU32::new(0);
+        let result = policy(vec![RetryableError::Io])
+            .run("add", || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(io_error())
+                } else {
+                    Ok("Qm")
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "Qm");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
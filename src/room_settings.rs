@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use matrix_sdk::{identifiers::RoomId, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::debug;
+use url::Url;
+
+use crate::config::MediaType;
+use crate::get_state_event;
+use crate::permissions::Permission;
+use crate::send_state_event;
+
+/// State event holding the per room settings. Only users allowed to send it may change them.
+pub const EVENT_TYPE: &str = "dev.nordgedanken.ipfs_bot.config";
+
+/// How long settings read from the room state are reused before fetching them again.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyStyle {
+    /// A notice that gets edited with the progress and finally the link.
+    Progress,
+    /// A single notice with the link once done.
+    Notice,
+    /// Status reactions on the command and a notice with the link once done.
+    Reactions,
+}
+
+/// Per room overrides of the global config. Unset values fall back to the config.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoomSettings {
+    /// Archive every media message without waiting for `!ipfs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_archive: Option<bool>,
+    /// Media types that get archived in this room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_types: Option<Vec<MediaType>>,
+    /// Gateway used for links in this room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_gateway: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_style: Option<ReplyStyle>,
+    /// Unpin archived media of this room after this many days.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u64>,
+    /// Who may trigger `!ipfs` in this room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_permission: Option<Permission>,
+    /// Ask the sender of the media before archiving it for somebody else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub require_consent: Option<bool>,
+    /// Encrypt files before adding them to IPFS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypt: Option<bool>,
+}
+
+impl RoomSettings {
+    pub const KEYS: &'static [&'static str] = &[
+        "auto_archive",
+        "allowed_types",
+        "preferred_gateway",
+        "reply_style",
+        "retention_days",
+        "archive_permission",
+        "require_consent",
+        "encrypt",
+    ];
+
+    fn check_key(key: &str) -> Result<(), String> {
+        if Self::KEYS.contains(&key) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Unknown setting '{}', known settings are: {}",
+                key,
+                Self::KEYS.join(", ")
+            ))
+        }
+    }
+
+    /// The value of a single setting, formatted for a notice.
+    pub fn get_key(&self, key: &str) -> Result<String, String> {
+        Self::check_key(key)?;
+        let settings = serde_json::to_value(self).unwrap();
+        Ok(match settings.get(key) {
+            Some(value) => value.to_string(),
+            None => "unset".to_string(),
+        })
+    }
+
+    /// Sets a single setting from user input. `unset` removes the override.
+    pub fn set_key(&mut self, key: &str, value: &str) -> Result<(), String> {
+        Self::check_key(key)?;
+        let mut settings = serde_json::to_value(&*self).unwrap();
+        let object = settings.as_object_mut().unwrap();
+        if value == "unset" {
+            object.remove(key);
+        } else {
+            let value: Value = serde_yaml::from_str(value)
+                .map_err(|e| format!("Invalid value for {}: {}", key, e))?;
+            object.insert(key.to_string(), value);
+        }
+
+        let settings: Self = serde_json::from_value(settings)
+            .map_err(|e| format!("Invalid value for {}: {}", key, e))?;
+        if let Some(gateway) = &settings.preferred_gateway {
+            Url::parse(gateway)
+                .map_err(|e| format!("Invalid value for preferred_gateway: {}", e))?;
+        }
+        *self = settings;
+        Ok(())
+    }
+}
+
+/// Reads and writes the settings state event, caching what was read.
+pub struct RoomSettingsStore {
+    client: Client,
+    cache: Mutex<HashMap<RoomId, (Instant, RoomSettings)>>,
+}
+
+impl RoomSettingsStore {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get(&self, room_id: &RoomId) -> RoomSettings {
+        if let Some((fetched, settings)) = self.cache.lock().unwrap().get(room_id) {
+            if fetched.elapsed() < CACHE_TTL {
+                return settings.clone();
+            }
+        }
+
+        let resp = self
+            .client
+            .send(get_state_event::Request {
+                room_id: room_id.clone(),
+                event_type: EVENT_TYPE.to_string(),
+                state_key: String::new(),
+            })
+            .await;
+        let settings = match resp {
+            Ok(resp) => serde_json::from_value(resp.content).unwrap_or_else(|e| {
+                debug!("Ignoring invalid room settings in {}: {}", room_id, e);
+                RoomSettings::default()
+            }),
+            // Most likely the room has no settings yet.
+            Err(e) => {
+                debug!("No room settings for {}: {:?}", room_id, e);
+                RoomSettings::default()
+            }
+        };
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(room_id.clone(), (Instant::now(), settings.clone()));
+        settings
+    }
+
+    pub async fn set(
+        &self,
+        room_id: &RoomId,
+        settings: &RoomSettings,
+    ) -> Result<(), matrix_sdk::Error> {
+        self.client
+            .send(send_state_event::Request {
+                room_id: room_id.clone(),
+                event_type: EVENT_TYPE.to_string(),
+                state_key: String::new(),
+                content: serde_json::to_value(settings).unwrap(),
+            })
+            .await?;
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(room_id.clone(), (Instant::now(), settings.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_and_gets_keys() {
+        let mut settings = RoomSettings::default();
+        assert_eq!(settings.get_key("auto_archive").unwrap(), "unset");
+
+        settings.set_key("auto_archive", "true").unwrap();
+        settings.set_key("allowed_types", "[image, video]").unwrap();
+        settings.set_key("reply_style", "reactions").unwrap();
+        settings.set_key("retention_days", "30").unwrap();
+        assert_eq!(settings.auto_archive, Some(true));
+        assert_eq!(
+            settings.allowed_types,
+            Some(vec![MediaType::Image, MediaType::Video])
+        );
+        assert_eq!(settings.reply_style, Some(ReplyStyle::Reactions));
+        assert_eq!(settings.get_key("retention_days").unwrap(), "30");
+        assert_eq!(settings.get_key("reply_style").unwrap(), "\"reactions\"");
+
+        settings.set_key("auto_archive", "unset").unwrap();
+        assert_eq!(settings.auto_archive, None);
+        assert_eq!(settings.get_key("auto_archive").unwrap(), "unset");
+    }
+
+    #[test]
+    fn keeps_the_settings_on_invalid_values() {
+        let mut settings = RoomSettings::default();
+        settings.set_key("retention_days", "30").unwrap();
+
+        assert!(settings.set_key("retention_days", "soon").is_err());
+        assert!(settings.set_key("reply_style", "loud").is_err());
+        assert!(settings.set_key("preferred_gateway", "not a url").is_err());
+        assert_eq!(settings.retention_days, Some(30));
+        assert_eq!(settings.reply_style, None);
+        assert_eq!(settings.preferred_gateway, None);
+    }
+
+    #[test]
+    fn refuses_unknown_keys() {
+        let mut settings = RoomSettings::default();
+        assert!(settings.set_key("colour", "blue").is_err());
+        assert!(settings.get_key("colour").is_err());
+        assert_eq!(settings, RoomSettings::default());
+    }
+}
@@ -0,0 +1,39 @@
+// Same as get_room_event: the ruma_api version has to match the one matrix-sdk uses
+use matrix_sdk::identifiers::{EventId, RoomId};
+use ruma_api::ruma_api;
+
+ruma_api! {
+    metadata {
+        description: "Send a state event with arbitrary type and JSON content to a room",
+        method: PUT,
+        name: "send_state_event",
+        path: "/_matrix/client/r0/rooms/:room_id/state/:event_type/:state_key",
+        rate_limited: false,
+        requires_authentication: true,
+    }
+
+    request {
+        /// The room to set the state in.
+        #[ruma_api(path)]
+        pub room_id: RoomId,
+
+        /// The type of event to send.
+        #[ruma_api(path)]
+        pub event_type: String,
+
+        /// The state key for the state being sent.
+        #[ruma_api(path)]
+        pub state_key: String,
+
+        /// The event content, sent as is.
+        #[ruma_api(body)]
+        pub content: serde_json::Value,
+    }
+
+    response {
+        /// A unique identifier for the event.
+        pub event_id: EventId,
+    }
+
+    error: matrix_sdk_common::api::Error
+}
@@ -0,0 +1,30 @@
+// Same as get_room_event: the ruma_api version has to match the one matrix-sdk uses
+use ruma_api::ruma_api;
+
+ruma_api! {
+    metadata {
+        description: "Send to-device events with arbitrary JSON content",
+        method: PUT,
+        name: "send_to_device",
+        path: "/_matrix/client/r0/sendToDevice/:event_type/:txn_id",
+        rate_limited: false,
+        requires_authentication: true,
+    }
+
+    request {
+        /// The type of the events to send.
+        #[ruma_api(path)]
+        pub event_type: String,
+
+        /// The transaction ID for these events.
+        #[ruma_api(path)]
+        pub txn_id: String,
+
+        /// The content per user and device, `*` meaning all devices of a user.
+        pub messages: serde_json::Value,
+    }
+
+    response {}
+
+    error: matrix_sdk_common::api::Error
+}
@@ -0,0 +1,111 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+/// Exit code if jobs were still running when the drain timeout ran out.
+pub const EXIT_JOBS_INTERRUPTED: i32 = 3;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long running jobs may take to finish after SIGINT or SIGTERM in seconds.
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 60,
+        }
+    }
+}
+
+/// Tracks running jobs and their temporary files so a shutdown can wait for them.
+#[derive(Default)]
+pub struct Shutdown {
+    stopping: AtomicBool,
+    active_jobs: AtomicUsize,
+    temp_files: Mutex<HashSet<PathBuf>>,
+}
+
+/// Counts as a running job until dropped. It owns the `Shutdown`, so it can be taken before
+/// a job gets spawned and moved into its task.
+pub struct JobGuard(Arc<Shutdown>);
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.0.active_jobs.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    /// `true` once a shutdown started, new commands are ignored from then on.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    pub fn active_jobs(&self) -> usize {
+        self.active_jobs.load(Ordering::SeqCst)
+    }
+
+    /// Counts a job as running. Jobs check `is_stopping` only after taking the guard, so a
+    /// drain either sees the job or the job sees the shutdown.
+    pub fn job(self: &Arc<Self>) -> JobGuard {
+        self.active_jobs.fetch_add(1, Ordering::SeqCst);
+        JobGuard(self.clone())
+    }
+
+    pub fn track_temp_file(&self, path: &Path) {
+        self.temp_files.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    pub fn untrack_temp_file(&self, path: &Path) {
+        self.temp_files.lock().unwrap().remove(path);
+    }
+
+    /// Stops accepting commands and waits up to `timeout` for running jobs.
+    /// Returns `false` if some were still running.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.stopping.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let active = self.active_jobs.load(Ordering::SeqCst);
+            if active == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                warn!("{} job(s) still running after {:?}", active, timeout);
+                return false;
+            }
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+        }
+    }
+
+    /// Removes the temporary files jobs left behind.
+    pub fn remove_temp_files(&self) {
+        for path in self.temp_files.lock().unwrap().drain() {
+            if path.exists() {
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Unable to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub async fn signal_received() {
+    let mut interrupt = signal(SignalKind::interrupt()).expect("unable to listen for SIGINT");
+    let mut terminate = signal(SignalKind::terminate()).expect("unable to listen for SIGTERM");
+    tokio::select! {
+        _ = interrupt.recv() => info!("SIGINT received, shutting down"),
+        _ = terminate.recv() => info!("SIGTERM received, shutting down"),
+    }
+}
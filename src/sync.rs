@@ -0,0 +1,47 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// What happens with commands sent while the bot was not running.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BacklogPolicy {
+    /// Only react to commands sent after the bot started.
+    Ignore,
+    /// Handle commands sent since the last stored sync token. Commands that already
+    /// have a job record are skipped.
+    CatchUp,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    pub backlog: BacklogPolicy,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            backlog: BacklogPolicy::Ignore,
+        }
+    }
+}
+
+pub fn load_token(path: &Path) -> Option<String> {
+    let token = fs::read_to_string(path).ok()?;
+    let token = token.trim();
+    if token.is_empty() {
+        None
+    } else {
+        Some(token.to_string())
+    }
+}
+
+pub fn save_token(path: &Path, token: &str) {
+    let tmp = path.with_extension("tmp");
+    if let Err(e) = fs::write(&tmp, token).and_then(|_| fs::rename(&tmp, path)) {
+        warn!("Unable to store the sync token: {}", e);
+    }
+}
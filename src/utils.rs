@@ -1,6 +1,40 @@
+use matrix_sdk::events::room::message::MessageEventContent;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::{env, process};
 use url::Url;
 
+use crate::config::MediaType;
+
+/// A piece of media referenced by a message.
+pub struct Media {
+    pub media_type: MediaType,
+    pub mxc_url: String,
+    pub filename: String,
+}
+
+/// Returns the media of Image, Video, File and Audio messages.
+pub fn media_source(content: &MessageEventContent) -> Option<Media> {
+    let (media_type, body, url, file) = match content {
+        MessageEventContent::Image(c) => (MediaType::Image, &c.body, &c.url, &c.file),
+        MessageEventContent::Video(c) => (MediaType::Video, &c.body, &c.url, &c.file),
+        MessageEventContent::File(c) => (MediaType::File, &c.body, &c.url, &c.file),
+        MessageEventContent::Audio(c) => (MediaType::Audio, &c.body, &c.url, &c.file),
+        _ => return None,
+    };
+    let mxc_url = match url {
+        Some(url) => url.clone(),
+        None => file.as_ref()?.url.clone(),
+    };
+    Some(Media {
+        media_type,
+        mxc_url,
+        filename: body.clone(),
+    })
+}
+
 pub fn get_media_download_url(mxc_url: String) -> String {
     let url_parts_raw = mxc_url.replace("mxc://", "");
     let url_parts: Vec<&str> = url_parts_raw.split('/').collect();
@@ -10,11 +44,36 @@ pub fn get_media_download_url(mxc_url: String) -> String {
     // FIX this madness
     let mut new_url = Url::parse(format!("https://matrix.{}", server_name).as_str()).unwrap();
     new_url.set_path(new_path.as_str());
-    println!("{}", new_url);
     new_url.to_string()
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Where the media of the job `job` is downloaded to. The name is unique per bot process and
+/// job, and only the last component of the filename from the event is kept.
+pub fn temp_file_path(job: u64, filename: &str) -> PathBuf {
+    env::temp_dir().join(format!(
+        "ipfs-bot-{}-{}-{}",
+        process::id(),
+        job,
+        sanitize_filename(filename)
+    ))
+}
+
+fn sanitize_filename(filename: &str) -> String {
+    let name = filename
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "media".to_string(),
+        _ => name,
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Session {
     /// The access token used for this session.
     pub access_token: String,
@@ -22,4 +81,63 @@ pub struct Session {
     pub user_id: String,
     /// The ID of the client device
     pub device_id: String,
+    /// The homeserver the session belongs to.
+    #[serde(default)]
+    pub homeserver: Option<String>,
+}
+
+/// Keeps the access token out of logs.
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("access_token", &"<redacted>")
+            .field("user_id", &self.user_id)
+            .field("device_id", &self.device_id)
+            .field("homeserver", &self.homeserver)
+            .finish()
+    }
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Option<Self> {
+        if !path.exists() {
+            return None;
+        }
+        let f = OpenOptions::new().read(true).open(path).unwrap();
+        Some(serde_json::from_reader(f).expect("file should be proper JSON"))
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string(self)?;
+        fs::write(path, json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_files_stay_in_the_temp_dir() {
+        for filename in &["../../etc/passwd", "..\\..\\boot.ini", "/tmp/x/", "..", ""] {
+            let path = temp_file_path(1, filename);
+            assert_eq!(
+                path.parent(),
+                Some(env::temp_dir().as_path()),
+                "{}",
+                filename
+            );
+        }
+        assert!(temp_file_path(1, "../../etc/passwd")
+            .ends_with(format!("ipfs-bot-{}-1-passwd", process::id())));
+        assert!(temp_file_path(1, "..").ends_with(format!("ipfs-bot-{}-1-media", process::id())));
+    }
+
+    #[test]
+    fn temp_files_of_jobs_differ() {
+        assert_ne!(temp_file_path(1, "cat.jpg"), temp_file_path(2, "cat.jpg"));
+        assert!(
+            temp_file_path(1, "cat.jpg").ends_with(format!("ipfs-bot-{}-1-cat.jpg", process::id()))
+        );
+    }
 }